@@ -0,0 +1,184 @@
+use std::fmt;
+
+use wasm_tracer_abi::{FunctionAggregate, FunctionMetadata, LEN_BUCKETS, ParamType};
+
+use crate::function_map::{FunctionMapReader, FunctionReport, MapSummary};
+
+/// The aggregates of the calls of a function, summed over every CPU.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FunctionSummary {
+    /// The calls that match the filters of the function
+    pub calls: u64,
+    /// The calls with a `&str` or `&[u8]` param that points outside of the linear memory, which
+    /// are not in `params`
+    pub invalid_guest_pointers: u64,
+    /// The statistics of every param, empty if no params are aggregated yet
+    pub params: Vec<ParamSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamSummary {
+    Signed {
+        min: i64,
+        max: i64,
+        sum: i128,
+    },
+    Unsigned {
+        min: u64,
+        max: u64,
+        sum: u128,
+    },
+    /// The log2 histogram of the lengths, see [`LEN_BUCKETS`]
+    Bytes {
+        lens: [u64; LEN_BUCKETS],
+    },
+    /// A param whose type is not aggregated
+    Other,
+}
+
+impl FunctionSummary {
+    /// The calls whose params are aggregated
+    pub fn aggregated_calls(&self) -> u64 {
+        self.calls - self.invalid_guest_pointers
+    }
+}
+
+impl MapSummary for FunctionSummary {
+    type Value = FunctionAggregate;
+
+    fn from_value(aggregate: &FunctionAggregate, meta: &FunctionMetadata) -> Self {
+        let params = (0..meta.param_count)
+            .map(|i| {
+                let param = &aggregate.params[i];
+                match meta.param_types[i] {
+                    ParamType::I8 | ParamType::I32 | ParamType::I64 => ParamSummary::Signed {
+                        min: param.min as i64,
+                        max: param.max as i64,
+                        sum: param.sum as i64 as i128,
+                    },
+                    ParamType::U8 | ParamType::U32 | ParamType::U64 => ParamSummary::Unsigned {
+                        min: param.min,
+                        max: param.max,
+                        sum: param.sum as u128,
+                    },
+                    ParamType::Bytes => ParamSummary::Bytes {
+                        lens: aggregate.bytes_lens[i],
+                    },
+                    ParamType::F32 | ParamType::F64 | ParamType::Unspecified => ParamSummary::Other,
+                }
+            })
+            .collect();
+
+        FunctionSummary {
+            calls: aggregate.calls,
+            invalid_guest_pointers: aggregate.invalid_guest_pointers,
+            params,
+        }
+    }
+
+    fn merge(&mut self, other: &FunctionSummary) {
+        // the statistics of the params are zeroed until a call is aggregated, so they are not
+        // merged
+        let has_params = self.aggregated_calls() > 0;
+        self.calls += other.calls;
+        self.invalid_guest_pointers += other.invalid_guest_pointers;
+
+        if other.aggregated_calls() == 0 {
+            return;
+        }
+        if !has_params {
+            self.params = other.params.clone();
+            return;
+        }
+
+        for (param, other) in self.params.iter_mut().zip(&other.params) {
+            match (param, other) {
+                (
+                    ParamSummary::Signed { min, max, sum },
+                    ParamSummary::Signed {
+                        min: other_min,
+                        max: other_max,
+                        sum: other_sum,
+                    },
+                ) => {
+                    *min = (*min).min(*other_min);
+                    *max = (*max).max(*other_max);
+                    *sum += other_sum;
+                }
+                (
+                    ParamSummary::Unsigned { min, max, sum },
+                    ParamSummary::Unsigned {
+                        min: other_min,
+                        max: other_max,
+                        sum: other_sum,
+                    },
+                ) => {
+                    *min = (*min).min(*other_min);
+                    *max = (*max).max(*other_max);
+                    *sum += other_sum;
+                }
+                (ParamSummary::Bytes { lens }, ParamSummary::Bytes { lens: other_lens }) => {
+                    lens.iter_mut()
+                        .zip(other_lens)
+                        .for_each(|(count, other)| *count += other);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The [`FunctionSummary`] of every traced function, keyed by the function name.
+pub type AggregateReport = FunctionReport<FunctionSummary>;
+
+/// Reads the aggregates of the functions from the `CallAggregates` map.
+pub type AggregateReader = FunctionMapReader<FunctionSummary>;
+
+impl fmt::Display for AggregateReport {
+    /// Writes a table of the calls of every function, followed by a row for every param.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<32} {:>12} {:>12}", "FUNCTION", "CALLS", "INVALID")?;
+        for (name, summary) in &self.functions {
+            write!(
+                f,
+                "\n{name:<32} {:>12} {:>12}",
+                summary.calls, summary.invalid_guest_pointers
+            )?;
+
+            let calls = summary.aggregated_calls();
+            for (i, param) in summary.params.iter().enumerate() {
+                match param {
+                    ParamSummary::Signed { min, max, sum } => write!(
+                        f,
+                        "\n  arg{i}: min {min} max {max} mean {:.2}",
+                        *sum as f64 / calls as f64
+                    )?,
+                    ParamSummary::Unsigned { min, max, sum } => write!(
+                        f,
+                        "\n  arg{i}: min {min} max {max} mean {:.2}",
+                        *sum as f64 / calls as f64
+                    )?,
+                    ParamSummary::Bytes { lens } => {
+                        write!(f, "\n  arg{i}: len")?;
+                        for (bucket, count) in lens.iter().enumerate() {
+                            if *count == 0 {
+                                continue;
+                            }
+                            match bucket {
+                                0 => write!(f, " 0: {count}")?,
+                                _ => write!(
+                                    f,
+                                    " {}..{}: {count}",
+                                    1u64 << (bucket - 1),
+                                    1u64 << bucket
+                                )?,
+                            }
+                        }
+                    }
+                    ParamSummary::Other => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail};
+use log::warn;
+use tokio::signal;
+use wasm_tracer::{
+    Tracer, chrome_trace::ChromeTraceSink, ebpf_runner::TraceMode, filter::FilterSpec,
+    perf_util::MappingSource, scheduler::BreakpointScheduler,
+};
+
+pub const USAGE: &str = "usage: wasm-trace attach --pid <PID> --module <PATH> [--bin-name <NAME>] \
+[--perf-map <PATH> | --jitdump <PATH>] [--trace <FUNCTION>]... [--exits] \
+[--capture-limit <BYTES>] [--stacks] [--chrome-trace <PATH>] \
+[--filter '<FUNCTION> arg<N> <OP> <VALUE>']... [--sample <FUNCTION>=<N>]... \
+[--rate-limit <FUNCTION>=<CALLS_PER_SEC>[/<BURST>]]... [--priority <FUNCTION>=<N>]... \
+[--aggregate [--report-interval <SECS>] | --latency]";
+
+/// How often the aggregates are printed unless it's configured otherwise
+const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Arguments of `wasm-trace attach`, which traces an already running wasmtime process.
+#[derive(Debug)]
+pub struct AttachArgs {
+    pub pid: u32,
+    /// The wasm module that the process runs, which is used to read the function signatures
+    pub module: PathBuf,
+    /// The prefix of the functions in the perf map, defaults to the file name of the `module`
+    pub bin_name: String,
+    pub mapping_source: MappingSource,
+    /// Functions to trace. The breakpoints are rotated across all the functions with a known
+    /// signature if this is empty.
+    pub functions: Vec<String>,
+    pub trace_exits: bool,
+    /// The maximum number of bytes that are captured from a `&str` or `&[u8]` param
+    pub capture_limit: Option<u16>,
+    /// Capture the call stack of every event
+    pub capture_stacks: bool,
+    /// Write the events as Chrome Trace Event JSON instead of printing them
+    pub chrome_trace: Option<PathBuf>,
+    /// Predicates on the params, the calls that don't match them are dropped in the kernel
+    pub filters: Vec<FilterSpec>,
+    /// Functions of which only every Nth call is traced
+    pub sampling: Vec<(String, u32)>,
+    /// Functions whose traced calls are limited to `(calls per second, burst)` on every CPU
+    pub rate_limits: Vec<(String, u32, u32)>,
+    /// Priorities of the `functions`, the ones with higher priorities get the hardware
+    /// breakpoint slots first
+    pub priorities: HashMap<String, u32>,
+    /// Whether the events are printed, or the calls are aggregated or timed in the kernel
+    pub mode: TraceMode,
+    /// How often the summary is printed when the calls are aggregated
+    pub report_interval: Duration,
+}
+
+impl AttachArgs {
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut pid = None;
+        let mut module = None;
+        let mut bin_name = None;
+        let mut mapping_source = None;
+        let mut functions = Vec::new();
+        let mut trace_exits = false;
+        let mut capture_limit = None;
+        let mut capture_stacks = false;
+        let mut chrome_trace = None;
+        let mut filters = Vec::new();
+        let mut sampling = Vec::new();
+        let mut rate_limits = Vec::new();
+        let mut priorities = HashMap::new();
+        let mut mode = TraceMode::Events;
+        let mut report_interval = DEFAULT_REPORT_INTERVAL;
+
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            let mut value = || it.next().ok_or(anyhow!("missing value for {arg}\n{USAGE}"));
+            match arg.as_str() {
+                "--pid" => pid = Some(value()?.parse()?),
+                "--module" => module = Some(PathBuf::from(value()?)),
+                "--bin-name" => bin_name = Some(value()?.clone()),
+                "--perf-map" => {
+                    mapping_source = Some(MappingSource::PerfMap(PathBuf::from(value()?)))
+                }
+                "--jitdump" => {
+                    mapping_source = Some(MappingSource::JitDump(PathBuf::from(value()?)))
+                }
+                "--trace" => functions.push(value()?.clone()),
+                "--exits" => trace_exits = true,
+                "--capture-limit" => capture_limit = Some(value()?.parse()?),
+                "--stacks" => capture_stacks = true,
+                "--chrome-trace" => chrome_trace = Some(PathBuf::from(value()?)),
+                "--filter" => filters.push(value()?.parse()?),
+                "--sample" => {
+                    let (function, n) = function_setting(value()?)?;
+                    sampling.push((function, n.parse()?));
+                }
+                "--rate-limit" => {
+                    let (function, limit) = function_setting(value()?)?;
+                    let (per_sec, burst) = match limit.split_once('/') {
+                        Some((per_sec, burst)) => (per_sec.parse()?, burst.parse()?),
+                        // a second worth of calls can be traced at once by default
+                        None => (limit.parse()?, limit.parse()?),
+                    };
+                    rate_limits.push((function, per_sec, burst));
+                }
+                "--priority" => {
+                    let (function, priority) = function_setting(value()?)?;
+                    priorities.insert(function, priority.parse()?);
+                }
+                "--aggregate" => mode = TraceMode::Aggregate,
+                "--latency" => mode = TraceMode::Latency,
+                "--report-interval" => report_interval = Duration::from_secs(value()?.parse()?),
+                _ => bail!("unknown argument {arg}\n{USAGE}"),
+            }
+        }
+
+        let pid = pid.ok_or(anyhow!("--pid is required\n{USAGE}"))?;
+        let module: PathBuf = module.ok_or(anyhow!("--module is required\n{USAGE}"))?;
+        let bin_name = match bin_name {
+            Some(bin_name) => bin_name,
+            None => module
+                .file_stem()
+                .ok_or(anyhow!("invalid module path {}", module.display()))?
+                .to_string_lossy()
+                .replace('-', "_"),
+        };
+
+        let mapping_source = mapping_source.unwrap_or_else(|| MappingSource::perf_map(pid));
+
+        if mode != TraceMode::Events && (capture_stacks || chrome_trace.is_some()) {
+            bail!(
+                "--aggregate and --latency cannot be used with --stacks or --chrome-trace\n{USAGE}"
+            );
+        }
+        // the calls are aggregated or timed before they are throttled in the probe
+        if mode != TraceMode::Events && (!sampling.is_empty() || !rate_limits.is_empty()) {
+            bail!(
+                "--aggregate and --latency cannot be used with --sample or --rate-limit\n{USAGE}"
+            );
+        }
+        if !priorities.is_empty() && functions.is_empty() {
+            bail!("--priority requires --trace\n{USAGE}");
+        }
+        if report_interval.is_zero() {
+            bail!("--report-interval must be positive\n{USAGE}");
+        }
+
+        Ok(AttachArgs {
+            pid,
+            module,
+            bin_name,
+            mapping_source,
+            functions,
+            trace_exits,
+            capture_limit,
+            capture_stacks,
+            chrome_trace,
+            filters,
+            sampling,
+            rate_limits,
+            priorities,
+            mode,
+            report_interval,
+        })
+    }
+}
+
+/// Splits a `<FUNCTION>=<VALUE>` argument.
+fn function_setting(arg: &str) -> anyhow::Result<(String, &str)> {
+    let (function, value) = arg.split_once('=').ok_or(anyhow!(
+        "invalid value {arg}, expected <FUNCTION>=<VALUE>\n{USAGE}"
+    ))?;
+    Ok((function.to_string(), value))
+}
+
+/// Traces the wasm functions in the process `args.pid` until Ctrl-C.
+pub async fn run(args: AttachArgs) -> anyhow::Result<()> {
+    let mut tracer = Tracer::builder()
+        .module(&args.module)
+        .bin_name(&args.bin_name)
+        .mapping_source(args.mapping_source)
+        .exits(args.trace_exits)
+        .stacks(args.capture_stacks)
+        .mode(args.mode);
+    if let Some(capture_limit) = args.capture_limit {
+        tracer = tracer.capture_limit(capture_limit);
+    }
+    for function in &args.functions {
+        tracer = tracer.trace_inferred(function);
+    }
+    for filter in args.filters {
+        tracer = tracer.filter(filter);
+    }
+    for (function, n) in args.sampling {
+        tracer = tracer.sample(function, n);
+    }
+    for (function, per_sec, burst) in args.rate_limits {
+        tracer = tracer.rate_limit(function, per_sec, burst);
+    }
+    for (function, priority) in args.priorities {
+        tracer = tracer.priority(function, priority);
+    }
+    if args.functions.is_empty() {
+        tracer = tracer.schedule(BreakpointScheduler::round_robin(Duration::from_millis(10)));
+    }
+    if let Some(path) = &args.chrome_trace {
+        tracer = tracer.sink(ChromeTraceSink::create(path)?.shared());
+    }
+
+    let handle = tracer.attach(args.pid).await?;
+    if let Some(report) = handle.attach_report() {
+        println!("{report}");
+        if report.attached.is_empty() {
+            warn!("no function could be attached");
+        }
+    }
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    println!("Tracing {}, waiting for Ctrl-C...", args.pid);
+    if args.mode == TraceMode::Aggregate {
+        let mut interval = tokio::time::interval(args.report_interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Some(aggregates) = handle.stats()?.aggregates {
+                        println!("{aggregates}\n");
+                    }
+                }
+                res = &mut ctrl_c => break res?,
+            }
+        }
+    } else {
+        ctrl_c.await?;
+    }
+    println!("Exiting...");
+
+    let stats = handle.stop().await?;
+    if let Some(path) = &args.chrome_trace {
+        println!("Wrote the trace to {}", path.display());
+    }
+    println!("{stats}");
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::{error::Error, fmt, io};
+
+use aya::programs::ProgramError;
+
+/// Why the breakpoints of a function cannot be attached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachFailure {
+    /// Every debug register is taken (`ENOSPC`), by the functions with a higher priority or by
+    /// another debugger
+    NoSlot,
+    /// The kernel refuses the address (`EINVAL`)
+    InvalidAddress,
+    /// The process cannot be traced by this user (`EACCES` or `EPERM`)
+    PermissionDenied,
+    /// The thread has exited (`ESRCH`)
+    NoThread,
+    /// Any other error, which is kept as its message
+    Other(String),
+}
+
+impl AttachFailure {
+    /// Finds the reason in the OS error that `e` is caused by.
+    pub fn from_error(e: &ProgramError) -> Self {
+        let mut source: Option<&(dyn Error + 'static)> = Some(e);
+        while let Some(error) = source {
+            if let Some(io_error) = error.downcast_ref::<io::Error>() {
+                return match io_error.raw_os_error() {
+                    Some(libc::ENOSPC) => AttachFailure::NoSlot,
+                    Some(libc::EINVAL) => AttachFailure::InvalidAddress,
+                    Some(libc::EACCES | libc::EPERM) => AttachFailure::PermissionDenied,
+                    Some(libc::ESRCH) => AttachFailure::NoThread,
+                    _ => AttachFailure::Other(io_error.to_string()),
+                };
+            }
+            source = error.source();
+        }
+
+        AttachFailure::Other(e.to_string())
+    }
+}
+
+impl fmt::Display for AttachFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachFailure::NoSlot => write!(f, "no free debug register (ENOSPC)"),
+            AttachFailure::InvalidAddress => write!(f, "the address is refused (EINVAL)"),
+            AttachFailure::PermissionDenied => write!(f, "permission denied (EACCES or EPERM)"),
+            AttachFailure::NoThread => write!(f, "the thread has exited (ESRCH)"),
+            AttachFailure::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// The functions that are traced by [`crate::ebpf_runner::EbpfRunner::attach_multi`] or in a
+/// window of [`crate::ebpf_runner::EbpfRunner::run_scheduler`], in the order that they are
+/// attached.
+#[derive(Debug, Default, Clone)]
+pub struct AttachReport {
+    /// The functions whose breakpoints are all attached
+    pub attached: Vec<String>,
+    /// The functions that are not traced, since one of their breakpoints cannot be attached
+    pub failed: Vec<(String, AttachFailure)>,
+}
+
+impl AttachReport {
+    /// Whether every function is traced
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for AttachReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Attached {} of {} functions:",
+            self.attached.len(),
+            self.attached.len() + self.failed.len()
+        )?;
+        for name in &self.attached {
+            write!(f, "\n  {name}: attached")?;
+        }
+        for (name, failure) in &self.failed {
+            write!(f, "\n  {name}: failed, {failure}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use wasm_tracer_abi::EventKind;
+
+use crate::{
+    event::{ArgValue, CompletedCall, TraceEvent},
+    sink::{EventSink, SharedSink},
+};
+
+/// Writes the events as a Chrome Trace Event JSON array, which can be opened in Perfetto or in
+/// `chrome://tracing`.
+///
+/// The completed calls are written as complete events (`"ph": "X"`) that span from the entry to
+/// the exit, and the events of the functions whose exits are not traced as instant events. The
+/// events are grouped by the process and the thread that they are captured in.
+pub struct ChromeTraceSink<W: Write> {
+    out: W,
+    /// Whether an event is written, since the events are separated by commas
+    started: bool,
+    /// Processes whose names are written as metadata events
+    named_processes: HashSet<u32>,
+    /// Whether the array is closed, after which the events are ignored so that the output stays
+    /// valid JSON
+    finished: bool,
+}
+
+impl ChromeTraceSink<BufWriter<fs::File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::new(BufWriter::new(fs::File::create(path)?))
+    }
+}
+
+impl<W: Write + Send + 'static> ChromeTraceSink<W> {
+    pub fn new(mut out: W) -> anyhow::Result<Self> {
+        out.write_all(b"[\n")?;
+        Ok(Self {
+            out,
+            started: false,
+            named_processes: HashSet::new(),
+            finished: false,
+        })
+    }
+
+    pub fn shared(self) -> SharedSink {
+        Arc::new(Mutex::new(self))
+    }
+
+    fn write_event(&mut self, event: &str) -> anyhow::Result<()> {
+        if self.started {
+            self.out.write_all(b",\n")?;
+        }
+        self.started = true;
+        self.out.write_all(event.as_bytes())?;
+        Ok(())
+    }
+
+    fn name_process(&mut self, tgid: u32) -> anyhow::Result<()> {
+        if !self.named_processes.insert(tgid) {
+            return Ok(());
+        }
+
+        // the process can already be gone
+        let Ok(comm) = fs::read_to_string(format!("/proc/{tgid}/comm")) else {
+            return Ok(());
+        };
+        self.write_event(&format!(
+            r#"{{"name":"process_name","ph":"M","pid":{tgid},"args":{{"name":{}}}}}"#,
+            json_string(comm.trim_end())
+        ))
+    }
+}
+
+impl<W: Write + Send + 'static> EventSink for ChromeTraceSink<W> {
+    fn event(&mut self, event: &TraceEvent) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.name_process(event.tgid)?;
+
+        let name = match event.kind {
+            EventKind::Entry | EventKind::InvalidGuestPointer => event.name.clone(),
+            EventKind::Exit => format!("{} (exit)", event.name),
+        };
+
+        let mut args = Args::default();
+        match event.kind {
+            EventKind::Entry | EventKind::InvalidGuestPointer => args.params(event),
+            EventKind::Exit => {
+                if let Some(ret) = event.args.first() {
+                    args.value("return", ret);
+                }
+            }
+        }
+        args.context(event);
+
+        self.write_event(&format!(
+            r#"{{"name":{},"cat":"wasm","ph":"i","s":"t","ts":{},"pid":{},"tid":{},"args":{}}}"#,
+            json_string(&name),
+            micros(event),
+            event.tgid,
+            event.tid,
+            args.finish()
+        ))
+    }
+
+    fn call(&mut self, call: &CompletedCall) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let entry = &call.entry;
+        self.name_process(entry.tgid)?;
+
+        let mut args = Args::default();
+        args.params(entry);
+        if let Some(ret) = &call.ret {
+            args.value("return", ret);
+        }
+        args.context(entry);
+
+        self.write_event(&format!(
+            r#"{{"name":{},"cat":"wasm","ph":"X","ts":{},"dur":{:.3},"pid":{},"tid":{},"args":{}}}"#,
+            json_string(&entry.name),
+            micros(entry),
+            call.duration.as_secs_f64() * 1e6,
+            entry.tgid,
+            entry.tid,
+            args.finish()
+        ))
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.out.write_all(b"\n]\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// The wall-clock time of the event in microseconds, which is the unit of the trace
+fn micros(event: &TraceEvent) -> String {
+    let since_epoch = event.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{:.3}", since_epoch.as_secs_f64() * 1e6)
+}
+
+/// The `args` object of an event.
+#[derive(Default)]
+struct Args {
+    fields: Vec<String>,
+}
+
+impl Args {
+    fn params(&mut self, event: &TraceEvent) {
+        for (i, arg) in event.args.iter().enumerate() {
+            self.value(&format!("arg{i}"), arg);
+        }
+        if let Some(invalid_pointer) = &event.invalid_pointer {
+            self.string("invalid_pointer", &invalid_pointer.to_string());
+        }
+    }
+
+    fn context(&mut self, event: &TraceEvent) {
+        self.raw("cpu", &event.cpu.to_string());
+        if !event.observed.is_empty() {
+            self.raw("window", &event.window.to_string());
+        }
+        if !event.backtrace.is_empty() {
+            let frames = event
+                .backtrace
+                .iter()
+                .map(|frame| json_string(&frame.to_string()))
+                .collect::<Vec<_>>();
+            self.raw("backtrace", &format!("[{}]", frames.join(",")));
+        }
+    }
+
+    fn value(&mut self, key: &str, value: &ArgValue) {
+        match value {
+            ArgValue::Signed(v) => self.raw(key, &v.to_string()),
+            ArgValue::Unsigned(v) => self.raw(key, &v.to_string()),
+            ArgValue::Bytes(_) | ArgValue::TruncatedBytes { .. } => {
+                self.string(key, &value.to_string())
+            }
+        }
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.raw(key, &json_string(value));
+    }
+
+    fn raw(&mut self, key: &str, value: &str) {
+        self.fields.push(format!("{}:{value}", json_string(key)));
+    }
+
+    fn finish(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
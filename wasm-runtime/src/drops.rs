@@ -0,0 +1,91 @@
+use std::fmt;
+
+use wasm_tracer_abi::{DROP_REASON_COUNT, DropCounters, DropReason, FunctionMetadata};
+
+use crate::function_map::{FunctionMapReader, FunctionReport, MapSummary};
+
+/// The calls of a function that are not sent to the userspace, counted by their [`DropReason`]
+/// and summed over every CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DropStats {
+    pub counts: [u64; DROP_REASON_COUNT],
+}
+
+impl DropStats {
+    pub fn count(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    /// The calls that are dropped because they cannot be captured, which does not include the
+    /// calls that don't match the filters
+    pub fn failed(&self) -> u64 {
+        self.total() - self.count(DropReason::Filtered)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+impl MapSummary for DropStats {
+    type Value = DropCounters;
+
+    fn from_value(counters: &DropCounters, _meta: &FunctionMetadata) -> Self {
+        DropStats {
+            counts: counters.counts,
+        }
+    }
+
+    fn merge(&mut self, other: &DropStats) {
+        self.counts
+            .iter_mut()
+            .zip(&other.counts)
+            .for_each(|(count, other)| *count += other);
+    }
+}
+
+/// The [`DropStats`] of every traced function, keyed by the function name.
+pub type DropReport = FunctionReport<DropStats>;
+
+/// Reads the dropped calls of the functions from the `DropCounts` map.
+pub type DropReader = FunctionMapReader<DropStats>;
+
+fn reason_name(reason: DropReason) -> &'static str {
+    match reason {
+        DropReason::RingBufferFull => "ring buffer full",
+        DropReason::ReadFailed => "read failed",
+        DropReason::UnsupportedType => "unsupported type",
+        DropReason::RecordTooLong => "record too long",
+        DropReason::NoLinearMemory => "no linear memory",
+        DropReason::InvalidMetadata => "invalid metadata",
+        DropReason::MapFull => "map full",
+        DropReason::Filtered => "filtered",
+    }
+}
+
+impl fmt::Display for DropReport {
+    /// Writes the reasons that the calls of every function are dropped for, the functions
+    /// without dropped calls are left out.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dropped calls:")?;
+        let mut any = false;
+        for (name, stats) in &self.functions {
+            if stats.total() == 0 {
+                continue;
+            }
+            any = true;
+
+            write!(f, "\n  {name}: {} dropped", stats.total())?;
+            for reason in DropReason::ALL {
+                let count = stats.count(reason);
+                if count > 0 {
+                    write!(f, ", {count} {}", reason_name(reason))?;
+                }
+            }
+        }
+        if !any {
+            write!(f, " none")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use gimli::{
+    AttributeValue, DebuggingInformationEntry, DwTag, Dwarf, EndianSlice, LittleEndian, Unit,
+    UnitOffset,
+};
+use log::debug;
+use wasm_tracer_abi::ParamType;
+use wasmparser::{Parser, Payload};
+
+use crate::{perf_util::short_name, signature::Signature};
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Reads the Rust signatures of the functions that have code in `wasm` from its `.debug_*`
+/// custom sections and lowers them to the types that the tracer understands, e.g.
+/// `concat_str(x: &str, y: &str) -> String` becomes `(I32, Bytes, Bytes)` where the first
+/// param is the pointer that the returned `String` is written to.
+///
+/// Functions that use types which cannot be lowered are skipped.
+pub fn read_signatures(wasm: &[u8]) -> anyhow::Result<HashMap<String, Signature>> {
+    let mut sections = HashMap::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        if let Payload::CustomSection(reader) = payload? {
+            if reader.name().starts_with(".debug_") {
+                sections.insert(reader.name(), reader.data());
+            }
+        }
+    }
+
+    let dwarf = Dwarf::load(|id| -> gimli::Result<_> {
+        Ok(EndianSlice::new(
+            sections.get(id.name()).copied().unwrap_or(&[]),
+            LittleEndian,
+        ))
+    })?;
+
+    let mut signatures = HashMap::new();
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            // only the concrete instances of the functions have code
+            if entry.tag() != gimli::DW_TAG_subprogram
+                || entry.attr_value(gimli::DW_AT_low_pc)?.is_none()
+            {
+                continue;
+            }
+
+            let origin = resolve_origin(&unit, entry)?;
+            let Some(name) = attr_string(&dwarf, &unit, &origin, gimli::DW_AT_name)? else {
+                continue;
+            };
+
+            match lower_signature(&dwarf, &unit, entry.offset(), &origin)? {
+                Some(signature) => {
+                    signatures
+                        .entry(short_name(&name).to_string())
+                        .or_insert(signature);
+                }
+                None => debug!("skipping {name} since its signature cannot be lowered"),
+            }
+        }
+    }
+
+    Ok(signatures)
+}
+
+fn lower_signature(
+    dwarf: &Dwarf<Reader<'_>>,
+    unit: &Unit<Reader<'_>>,
+    offset: UnitOffset,
+    origin: &DebuggingInformationEntry<'_, '_, Reader<'_>>,
+) -> anyhow::Result<Option<Signature>> {
+    let mut params = Vec::new();
+
+    let ret_type = match type_of(dwarf, unit, origin)? {
+        None => ParamType::Unspecified,
+        Some((tag, name)) => match lower_type(tag, &name) {
+            // aggregates are written to the memory that the first param points to
+            Some(ParamType::Bytes) | None if tag == gimli::DW_TAG_structure_type => {
+                params.push(ParamType::I32);
+                ParamType::Unspecified
+            }
+            None => return Ok(None),
+            Some(ty) => ty,
+        },
+    };
+
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        let child = child.entry();
+        if child.tag() != gimli::DW_TAG_formal_parameter {
+            continue;
+        }
+
+        let param = resolve_origin(unit, child)?;
+        let Some((tag, name)) = type_of(dwarf, unit, &param)? else {
+            return Ok(None);
+        };
+        let Some(ty) = lower_type(tag, &name) else {
+            return Ok(None);
+        };
+        params.push(ty);
+    }
+
+    Ok(Some(Signature { params, ret_type }))
+}
+
+fn lower_type(tag: DwTag, name: &str) -> Option<ParamType> {
+    let ty = match tag {
+        gimli::DW_TAG_base_type => match name {
+            "u8" | "bool" => ParamType::U8,
+            "i8" => ParamType::I8,
+            "u16" | "u32" | "usize" | "char" => ParamType::U32,
+            "i16" | "i32" | "isize" => ParamType::I32,
+            "u64" => ParamType::U64,
+            "i64" => ParamType::I64,
+            "f32" => ParamType::F32,
+            "f64" => ParamType::F64,
+            _ => return None,
+        },
+        // pointers are 32 bits in wasm32
+        gimli::DW_TAG_pointer_type => ParamType::U32,
+        // fat pointers are lowered to `(pointer, length)` pairs
+        gimli::DW_TAG_structure_type => match name {
+            "&str" | "&mut str" | "&[u8]" | "&mut [u8]" => ParamType::Bytes,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(ty)
+}
+
+/// Returns the tag and the name of the type that `entry` refers to with `DW_AT_type`.
+fn type_of(
+    dwarf: &Dwarf<Reader<'_>>,
+    unit: &Unit<Reader<'_>>,
+    entry: &DebuggingInformationEntry<'_, '_, Reader<'_>>,
+) -> anyhow::Result<Option<(DwTag, String)>> {
+    let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(gimli::DW_AT_type)? else {
+        return Ok(None);
+    };
+
+    let ty = unit.entry(offset)?;
+    let name = attr_string(dwarf, unit, &ty, gimli::DW_AT_name)?.unwrap_or_default();
+
+    Ok(Some((ty.tag(), name)))
+}
+
+/// Follows `DW_AT_abstract_origin` or `DW_AT_specification` of `entry`, which is where the
+/// name and the types are defined for the concrete instances.
+fn resolve_origin<'a>(
+    unit: &'a Unit<Reader<'a>>,
+    entry: &DebuggingInformationEntry<'_, '_, Reader<'a>>,
+) -> anyhow::Result<DebuggingInformationEntry<'a, 'a, Reader<'a>>> {
+    for attr in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(attr)? {
+            return Ok(unit.entry(offset)?);
+        }
+    }
+
+    Ok(unit.entry(entry.offset())?)
+}
+
+fn attr_string(
+    dwarf: &Dwarf<Reader<'_>>,
+    unit: &Unit<Reader<'_>>,
+    entry: &DebuggingInformationEntry<'_, '_, Reader<'_>>,
+    attr: gimli::DwAt,
+) -> anyhow::Result<Option<String>> {
+    let Some(value) = entry.attr_value(attr)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(
+        dwarf
+            .attr_string(unit, value)?
+            .to_string_lossy()
+            .into_owned(),
+    ))
+}
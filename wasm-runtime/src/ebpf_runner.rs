@@ -1,31 +1,166 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use aya::{
     Ebpf,
-    maps::{HashMap as EbpfHashMap, RingBuf},
+    maps::{Array, HashMap as EbpfHashMap, MapData, PerCpuHashMap, RingBuf},
     programs::{
-        PerfEvent,
-        perf_event::{BreakpointConfig, PerfEventConfig, PerfEventScope, SamplePolicy},
+        PerfEvent, ProgramError, UProbe,
+        perf_event::{
+            BreakpointConfig, PerfEventConfig, PerfEventLinkId, PerfEventScope, SamplePolicy,
+        },
+        uprobe::UProbeAttachLocation,
     },
 };
-use log::warn;
+use log::{debug, warn};
+use tokio::{sync::oneshot, task::JoinHandle, time::Interval};
+
+use crate::{
+    aggregate::AggregateReader,
+    attach_report::{AttachFailure, AttachReport},
+    drops::DropReader,
+    event::{self, CallTracker, TraceEvent},
+    latency::LatencyReader,
+    perf_util::FunctionMapping,
+    proc_maps,
+    scheduler::{BreakpointScheduler, Window},
+    sink::SharedSink,
+    symbolize::Symbolizer,
+    throttle::CallStatsReader,
+};
 
-use crate::{FunctionCallEvent, perf_util::FunctionMapping};
+/// The number of the most recent windows that are kept to decode the events, which lag behind the
+/// scheduler by the time they spend in the ring buffer
+const RETAINED_WINDOWS: u64 = 128;
 
 pub struct EbpfRunner {
     ebpf: Ebpf,
-    traced_addresses: Vec<u64>,
+    /// The process that is traced
+    pid: u32,
+    /// The threads of the process that the breakpoints are attached to, which are refreshed
+    /// when the mapping is reloaded
+    threads: Vec<u32>,
+    /// Signatures of the functions to trace, which are traced as soon as they show up in the
+    /// function mapping
+    function_abi: HashMap<String, wasm_tracer_abi::FunctionMetadata>,
+    trace_exits: bool,
+    traced_functions: Arc<Mutex<HashMap<u64, TracedFunction>>>,
+    /// The functions that are not traced anymore, in the order that they are removed, which are
+    /// kept until `read_events` has read the events that they queued before their removal
+    retired_functions: Arc<Mutex<Vec<(u64, TracedFunction)>>>,
+    /// Breakpoints of every function in every thread, keyed by the function address. This is
+    /// only set after `attach_multi`, since the other modes manage their own probes.
+    links: Option<HashMap<u64, Vec<ThreadLink>>>,
+    /// The functions whose breakpoints did not fit with `attach_multi`, keyed by the function
+    /// address. They are not traced, and they are retried whenever the mapping is reloaded.
+    pending: HashMap<u64, TracedFunction>,
+    /// Windows of the breakpoint scheduler, keyed by their ids
+    windows: Arc<Mutex<HashMap<u64, Window>>>,
+    watcher: Option<MappingWatcher>,
+    /// Symbolizes the call stacks of the events, only set if the stacks are captured
+    symbolizer: Option<Arc<Mutex<Symbolizer>>>,
+    /// Filters of the functions, keyed by the function names
+    filters: HashMap<String, wasm_tracer_abi::FunctionFilters>,
+    /// Priorities of the functions in `attach_multi`, keyed by the function names
+    priorities: HashMap<String, u32>,
+    /// Counters of the calls that are skipped by the sampling and the rate limits
+    call_stats: CallStatsReader,
+    /// Counters of the calls that are dropped in the kernel, by their reasons
+    drops: DropReader,
+    mode: TraceMode,
+    /// Aggregates of the calls, only updated in [`TraceMode::Aggregate`]
+    aggregates: AggregateReader,
+    /// Latencies of the calls, only updated in [`TraceMode::Latency`]
+    latencies: LatencyReader,
+}
+
+/// What the eBPF program does with the traced calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceMode {
+    /// Every call is sent to `read_events`
+    #[default]
+    Events,
+    /// The calls are counted and their params are aggregated in the kernel, which is read with
+    /// [`EbpfRunner::aggregates`]. No events are sent, and the exits are not traced.
+    Aggregate,
+    /// The latencies of the calls are measured from their entries to their exits in the kernel,
+    /// which is read with [`EbpfRunner::latencies`]. No events are sent, and the exits are always
+    /// traced.
+    Latency,
+}
+
+/// Periodically reloads the function mapping to follow the modules that are compiled or dropped
+/// after the tracing has started.
+pub struct MappingWatcher {
+    interval: Interval,
+    reload: Box<dyn FnMut() -> anyhow::Result<FunctionMapping> + Send>,
+}
+
+impl MappingWatcher {
+    pub fn new(
+        period: Duration,
+        reload: impl FnMut() -> anyhow::Result<FunctionMapping> + Send + 'static,
+    ) -> Self {
+        Self {
+            interval: tokio::time::interval(period),
+            reload: Box::new(reload),
+        }
+    }
+}
+
+/// The functions that are started or stopped being traced by [`EbpfRunner::update_mapping`].
+#[derive(Debug, Default)]
+pub struct MappingUpdate {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The functions that are added, but whose breakpoints cannot be attached yet. They are
+    /// retried on the next reloads, and listed in `added` once they are attached.
+    pub failed: Vec<(String, AttachFailure)>,
+}
+
+/// A breakpoint and the thread that it's attached to
+type ThreadLink = (u32, PerfEventLinkId);
+
+#[derive(Debug, Clone)]
+pub struct TracedFunction {
+    pub name: String,
+    pub meta: wasm_tracer_abi::FunctionMetadata,
+    /// Addresses of the `ret` instructions of the function, empty if the exits are not traced
+    pub exit_sites: Vec<u64>,
 }
 
 impl EbpfRunner {
+    /// Loads the eBPF program to trace the process `pid`, which is `std::process::id()` when
+    /// the wasm module is run by this process. The call stack of every event is captured and
+    /// symbolized if `capture_stacks` is set.
     pub async fn load<P: AsRef<Path>>(
         path: P,
-        mem_base: u64,
+        pid: u32,
         function_abi: HashMap<String, wasm_tracer_abi::FunctionMetadata>,
         mapping: FunctionMapping,
+        trace_exits: bool,
+        capture_stacks: bool,
+        mode: TraceMode,
     ) -> anyhow::Result<Self> {
         let mut ebpf = aya::EbpfLoader::new()
-            .override_global("MEM_BASE", &mem_base, true)
+            .override_global("CAPTURE_STACKS", &(capture_stacks as u8), true)
+            .override_global(
+                "AGGREGATE_CALLS",
+                &((mode == TraceMode::Aggregate) as u8),
+                true,
+            )
+            .override_global(
+                "MEASURE_LATENCY",
+                &((mode == TraceMode::Latency) as u8),
+                true,
+            )
             .load(&fs::read(path)?)
             .unwrap();
 
@@ -48,85 +183,808 @@ impl EbpfRunner {
             }
         }
 
-        let mut func_types: EbpfHashMap<_, u64, wasm_tracer_abi::FunctionMetadata> =
-            EbpfHashMap::try_from(ebpf.map_mut("FunctionTypes").expect("map exists"))?;
+        let traced_functions: Arc<Mutex<HashMap<u64, TracedFunction>>> = Default::default();
+        let call_counters =
+            PerCpuHashMap::try_from(ebpf.take_map("CallCounters").expect("map exists"))?;
+        let drop_counts =
+            PerCpuHashMap::try_from(ebpf.take_map("DropCounts").expect("map exists"))?;
+        let aggregates =
+            PerCpuHashMap::try_from(ebpf.take_map("CallAggregates").expect("map exists"))?;
+        let latencies =
+            PerCpuHashMap::try_from(ebpf.take_map("CallLatencies").expect("map exists"))?;
 
-        let mut traced_addresses = Vec::new();
+        let mut runner = Self {
+            ebpf,
+            pid,
+            threads: proc_maps::threads(pid)?,
+            function_abi,
+            trace_exits: match mode {
+                TraceMode::Events => trace_exits,
+                // the exits are ignored when the calls are aggregated
+                TraceMode::Aggregate => false,
+                TraceMode::Latency => true,
+            },
+            traced_functions: traced_functions.clone(),
+            retired_functions: Default::default(),
+            links: None,
+            pending: HashMap::new(),
+            windows: Default::default(),
+            watcher: None,
+            symbolizer: capture_stacks.then(|| Arc::new(Mutex::new(Symbolizer::new(pid)))),
+            filters: HashMap::new(),
+            priorities: HashMap::new(),
+            call_stats: CallStatsReader::new(call_counters, traced_functions.clone()),
+            drops: DropReader::new(drop_counts, traced_functions.clone()),
+            mode,
+            aggregates: AggregateReader::new(aggregates, traced_functions.clone()),
+            latencies: LatencyReader::new(latencies, traced_functions),
+        };
+
+        runner.update_mapping(&mapping)?;
+
+        Ok(runner)
+    }
 
-        mapping.into_iter().for_each(|(addr, meta)| {
-            if let Some(meta) = function_abi.get(&meta.name) {
-                traced_addresses.push(*addr);
-                func_types.insert(addr, meta, 0).unwrap();
+    /// Reloads the function mapping with `reload` every `period` while `run_scheduler` or
+    /// `run_mapping_watcher` is running. The functions that show up in the mapping are traced,
+    /// and the functions whose code is unmapped are not traced anymore.
+    ///
+    /// New functions get breakpoints only when they are traced with `attach_multi` or
+    /// `run_scheduler`, uprobes need to be attached to the new modules explicitly.
+    pub fn set_mapping_watcher(
+        &mut self,
+        period: Duration,
+        reload: impl FnMut() -> anyhow::Result<FunctionMapping> + Send + 'static,
+    ) {
+        self.watcher = Some(MappingWatcher::new(period, reload));
+    }
+
+    /// Sets the filters of the functions, which are compiled with
+    /// [`crate::filter::compile_filters`]. The calls that don't match the filters of their
+    /// functions are dropped in the kernel. This replaces the filters that are set before.
+    pub fn set_filters(
+        &mut self,
+        filters: HashMap<String, wasm_tracer_abi::FunctionFilters>,
+    ) -> anyhow::Result<()> {
+        let traced_functions = self.traced_functions.lock().unwrap().clone();
+
+        let mut func_filters: EbpfHashMap<_, u64, wasm_tracer_abi::FunctionFilters> =
+            EbpfHashMap::try_from(self.ebpf.map_mut("FunctionFilters").expect("map exists"))?;
+        for (addr, function) in &traced_functions {
+            match filters.get(&function.name) {
+                Some(function_filters) => func_filters.insert(addr, function_filters, 0)?,
+                None => {
+                    let _ = func_filters.remove(addr);
+                }
             }
+        }
+
+        self.filters = filters;
+
+        Ok(())
+    }
+
+    /// Sets the priorities of the functions, which decide the order that `attach_multi` attaches
+    /// them in. The functions with higher priorities get the hardware breakpoint slots first,
+    /// and the functions that are not in `priorities` have the priority `0`.
+    pub fn set_priorities(&mut self, priorities: HashMap<String, u32>) {
+        self.priorities = priorities;
+    }
+
+    /// Returns a reader of the calls that are skipped by the sampling and the rate limits of the
+    /// functions, which are set in their [`wasm_tracer_abi::FunctionMetadata`].
+    pub fn call_stats(&self) -> CallStatsReader {
+        self.call_stats.clone()
+    }
+
+    /// Returns a reader of the calls that are dropped in the kernel, because they don't match
+    /// their filters or cannot be captured. A call that is not in the events, the aggregates or
+    /// the latencies is either in here or not made at all, apart from the throttled calls in
+    /// [`EbpfRunner::call_stats`].
+    pub fn drop_stats(&self) -> DropReader {
+        self.drops.clone()
+    }
+
+    /// Returns a reader of the aggregates of the calls, which are only updated in
+    /// [`TraceMode::Aggregate`].
+    pub fn aggregates(&self) -> AggregateReader {
+        self.aggregates.clone()
+    }
+
+    /// Returns a reader of the latencies of the calls, which are only measured in
+    /// [`TraceMode::Latency`].
+    pub fn latencies(&self) -> LatencyReader {
+        self.latencies.clone()
+    }
+
+    /// Syncs the traced functions with `mapping`.
+    ///
+    /// A function stops being traced when it is not in `mapping` anymore or its code is not
+    /// mapped as executable in the process, which is the case when its module is dropped.
+    pub fn update_mapping(&mut self, mapping: &FunctionMapping) -> anyhow::Result<MappingUpdate> {
+        self.sync_threads()?;
+
+        let executable = proc_maps::executable_ranges(self.pid)?;
+        let is_live = |addr: u64| executable.iter().any(|range| range.contains(&addr));
+
+        if let Some(symbolizer) = &self.symbolizer {
+            symbolizer.lock().unwrap().set_functions(mapping);
+        }
+
+        let mut update = MappingUpdate::default();
+
+        let removed = self
+            .traced_functions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(addr, function)| {
+                !is_live(**addr)
+                    || mapping
+                        .get(**addr)
+                        .is_none_or(|perf_meta| perf_meta.name != function.name)
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for addr in removed {
+            update.removed.push(self.remove_function(addr)?.name);
+        }
+
+        // the removed functions can leave room for the pending ones, which are retried before
+        // the new functions get the slots that are left
+        self.pending.retain(|addr, function| {
+            is_live(*addr)
+                && mapping
+                    .get(*addr)
+                    .is_some_and(|perf_meta| perf_meta.name == function.name)
         });
+        let mut retried = self.pending.drain().collect::<Vec<_>>();
+        retried.sort_by_key(|(addr, function)| {
+            let priority = self.priorities.get(&function.name).copied().unwrap_or(0);
+            (Reverse(priority), function.name.clone(), *addr)
+        });
+        for (addr, function) in retried {
+            let name = function.name.clone();
+            if self.insert_function(addr, function)?.is_none() {
+                update.added.push(name);
+            }
+        }
 
-        Ok(Self {
-            ebpf,
-            traced_addresses,
-        })
+        for (addr, perf_meta) in mapping {
+            if !is_live(*addr)
+                || self.pending.contains_key(addr)
+                || self.traced_functions.lock().unwrap().contains_key(addr)
+            {
+                continue;
+            }
+            let Some(meta) = self.function_abi.get(&perf_meta.name) else {
+                continue;
+            };
+
+            let exit_sites = if self.trace_exits {
+                perf_meta.return_sites(self.pid)?
+            } else {
+                Vec::new()
+            };
+
+            let failure = self.insert_function(
+                *addr,
+                TracedFunction {
+                    name: perf_meta.name.clone(),
+                    meta: *meta,
+                    exit_sites,
+                },
+            )?;
+            match failure {
+                Some(failure) => update.failed.push((perf_meta.name.clone(), failure)),
+                None => update.added.push(perf_meta.name.clone()),
+            }
+        }
+
+        Ok(update)
     }
 
-    pub fn attach_multi(&mut self) -> anyhow::Result<()> {
-        let program: &mut PerfEvent = self
-            .ebpf
-            .program_mut("trace_function_call")
+    /// Starts tracing the function at `addr`, and returns why its breakpoints cannot be attached
+    /// if they are attached by `attach_multi` but are not available. The function is moved to
+    /// the pending functions then, which are retried on every reload of the mapping.
+    fn insert_function(
+        &mut self,
+        addr: u64,
+        function: TracedFunction,
+    ) -> anyhow::Result<Option<AttachFailure>> {
+        // the function is known by `read_events` before any event can be produced for it
+        self.traced_functions
+            .lock()
             .unwrap()
-            .try_into()
-            .unwrap();
-        program.load().unwrap();
-
-        self.traced_addresses.iter().for_each(|address| {
-            println!("addr: {:x}", address);
-            program
-                .attach(
-                    PerfEventConfig::Breakpoint(BreakpointConfig::Instruction {
-                        address: *address,
-                    }),
-                    PerfEventScope::OneProcess {
-                        pid: std::process::id(),
-                        cpu: None,
-                    },
-                    SamplePolicy::Period(1),
-                    false,
-                )
-                .unwrap();
+            .insert(addr, function.clone());
+
+        // the filters are in place before the function can produce any event
+        if let Some(function_filters) = self.filters.get(&function.name) {
+            let mut func_filters: EbpfHashMap<_, u64, wasm_tracer_abi::FunctionFilters> =
+                EbpfHashMap::try_from(self.ebpf.map_mut("FunctionFilters").expect("map exists"))?;
+            func_filters.insert(addr, function_filters, 0)?;
+        }
+
+        let mut func_types: EbpfHashMap<_, u64, wasm_tracer_abi::FunctionMetadata> =
+            EbpfHashMap::try_from(self.ebpf.map_mut("FunctionTypes").expect("map exists"))?;
+        func_types.insert(addr, function.meta, 0)?;
+
+        let mut func_exits: EbpfHashMap<_, u64, u64> =
+            EbpfHashMap::try_from(self.ebpf.map_mut("FunctionExits").expect("map exists"))?;
+        for exit_site in &function.exit_sites {
+            func_exits.insert(exit_site, addr, 0)?;
+        }
+
+        if let Some(links) = &mut self.links {
+            let program = breakpoint_program(&mut self.ebpf)?;
+
+            // the functions that show up later get the slots that are left
+            match attach_function(program, &self.threads, addr, &function) {
+                Ok(function_links) => {
+                    links.insert(addr, function_links);
+                }
+                Err(failure) => {
+                    self.defer_function(addr)?;
+                    return Ok(Some(failure));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn remove_function(&mut self, addr: u64) -> anyhow::Result<TracedFunction> {
+        let function = self.traced_functions.lock().unwrap()[&addr].clone();
+
+        if let Some(function_links) = self.links.as_mut().and_then(|links| links.remove(&addr)) {
+            let program = breakpoint_program(&mut self.ebpf)?;
+            for (_, link) in function_links {
+                program.detach(link)?;
+            }
+        }
+
+        self.unregister_function(addr, &function)?;
+
+        self.call_stats.retire(addr, &function)?;
+        self.drops.retire(addr, &function)?;
+        match self.mode {
+            TraceMode::Events => {}
+            TraceMode::Aggregate => self.aggregates.retire(addr, &function)?,
+            TraceMode::Latency => self.latencies.retire(addr, &function)?,
+        }
+
+        // the function is removed after its probes are gone, so no new events show up for it,
+        // and it's kept until the events that are still in the ring buffer are decoded
+        if self.mode == TraceMode::Events {
+            self.retired_functions
+                .lock()
+                .unwrap()
+                .push((addr, function.clone()));
+        }
+        self.traced_functions.lock().unwrap().remove(&addr);
+
+        Ok(function)
+    }
+
+    /// Removes the function at `addr` from the maps of the probe.
+    fn unregister_function(&mut self, addr: u64, function: &TracedFunction) -> anyhow::Result<()> {
+        let mut func_exits: EbpfHashMap<_, u64, u64> =
+            EbpfHashMap::try_from(self.ebpf.map_mut("FunctionExits").expect("map exists"))?;
+        for exit_site in &function.exit_sites {
+            func_exits.remove(exit_site)?;
+        }
+
+        let mut func_types: EbpfHashMap<_, u64, wasm_tracer_abi::FunctionMetadata> =
+            EbpfHashMap::try_from(self.ebpf.map_mut("FunctionTypes").expect("map exists"))?;
+        func_types.remove(&addr)?;
+
+        if self.filters.contains_key(&function.name) {
+            let mut func_filters: EbpfHashMap<_, u64, wasm_tracer_abi::FunctionFilters> =
+                EbpfHashMap::try_from(self.ebpf.map_mut("FunctionFilters").expect("map exists"))?;
+            func_filters.remove(&addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a function whose breakpoints cannot be attached to the pending functions. It
+    /// cannot produce any event without breakpoints, so it's forgotten until it's retried.
+    fn defer_function(&mut self, addr: u64) -> anyhow::Result<()> {
+        let Some(function) = self.traced_functions.lock().unwrap().remove(&addr) else {
+            return Ok(());
+        };
+        self.unregister_function(addr, &function)?;
+        self.pending.insert(addr, function);
+        Ok(())
+    }
+
+    /// Follows the threads of the process, so that the breakpoints of `attach_multi` are also
+    /// attached in the threads that are started later, and the ones of the exited threads are
+    /// closed. The breakpoints of `run_scheduler` follow the threads on the next window.
+    fn sync_threads(&mut self) -> anyhow::Result<()> {
+        let threads = proc_maps::threads(self.pid)?;
+
+        if let Some(links) = &mut self.links {
+            let program = breakpoint_program(&mut self.ebpf)?;
+            let traced_functions = self.traced_functions.lock().unwrap();
+
+            for (addr, function_links) in links.iter_mut() {
+                let (exited, running) = function_links
+                    .drain(..)
+                    .partition::<Vec<_>, _>(|(tid, _)| threads.binary_search(tid).is_err());
+                *function_links = running;
+                detach_links(program, exited, &traced_functions[addr].name);
+
+                let started = threads
+                    .iter()
+                    .filter(|tid| self.threads.binary_search(tid).is_err());
+                for tid in started {
+                    let function = &traced_functions[addr];
+                    match attach_thread(program, *tid, *addr, function) {
+                        Ok(thread_links) => function_links.extend(thread_links),
+                        Err(AttachFailure::NoThread) => {}
+                        Err(failure) => {
+                            warn!(
+                                "failed to trace {} in thread {tid}: {failure}",
+                                function.name
+                            )
+                        }
+                    }
+                }
+            }
+        }
+
+        self.threads = threads;
+
+        Ok(())
+    }
+
+    /// Runs the mapping watcher that is set with `set_mapping_watcher` until the task is
+    /// cancelled. This is not needed with `run_scheduler`, which runs the watcher itself.
+    pub async fn run_mapping_watcher(&mut self) -> anyhow::Result<()> {
+        let mut watcher = self.watcher.take();
+        loop {
+            self.on_mapping_reload(next_mapping(&mut watcher).await);
+        }
+    }
+
+    fn on_mapping_reload(&mut self, mapping: anyhow::Result<FunctionMapping>) {
+        match mapping.and_then(|mapping| self.update_mapping(&mapping)) {
+            Ok(update) => {
+                update
+                    .added
+                    .iter()
+                    .for_each(|name| println!("started tracing {name}"));
+                update
+                    .removed
+                    .iter()
+                    .for_each(|name| println!("stopped tracing {name}"));
+                update
+                    .failed
+                    .iter()
+                    .for_each(|(name, failure)| println!("failed to trace {name}: {failure}"));
+            }
+            // the mapping can be read while it's being written, so it's retried on the next tick
+            Err(e) => warn!("failed to update the function mapping: {e}"),
+        }
+    }
+
+    /// Attaches hardware breakpoints to all the traced functions at once, in the order of their
+    /// priorities that are set with `set_priorities`. The functions whose breakpoints cannot be
+    /// attached, e.g. since the debug registers run out, are not traced and are listed in the
+    /// report. They are retried whenever the mapping is reloaded, and traced once the slots of
+    /// the removed functions free up.
+    pub fn attach_multi(&mut self) -> anyhow::Result<AttachReport> {
+        let mut functions = self
+            .traced_functions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, function)| (*addr, function.clone()))
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|(addr, function)| {
+            let priority = self.priorities.get(&function.name).copied().unwrap_or(0);
+            (Reverse(priority), function.name.clone(), *addr)
         });
 
+        let program = breakpoint_program(&mut self.ebpf)?;
+        program.load()?;
+
+        let mut links = HashMap::new();
+        let mut report = AttachReport::default();
+        let mut deferred = Vec::new();
+        for (addr, function) in functions {
+            // a function that doesn't fit can leave room for a smaller one, so every function is
+            // tried
+            match attach_function(program, &self.threads, addr, &function) {
+                Ok(function_links) => {
+                    links.insert(addr, function_links);
+                    report.attached.push(function.name);
+                }
+                Err(failure) => {
+                    deferred.push(addr);
+                    report.failed.push((function.name, failure));
+                }
+            }
+        }
+        self.links = Some(links);
+        for addr in deferred {
+            self.defer_function(addr)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Attaches the breakpoints to a different set of functions on every time slice, so that
+    /// more functions than the hardware breakpoint slots can be observed. Every event records
+    /// the window that it's captured in, and the functions that are observed in that window.
+    ///
+    /// A function whose breakpoints cannot be attached is left out of the window.
+    ///
+    /// This runs until the task is cancelled, so `read_events` should be called before this.
+    pub async fn run_scheduler(
+        &mut self,
+        mut scheduler: BreakpointScheduler,
+    ) -> anyhow::Result<()> {
+        let program = breakpoint_program(&mut self.ebpf)?;
+        program.load()?;
+
+        let mut links: Vec<PerfEventLinkId> = Vec::new();
+        let mut interval = tokio::time::interval(scheduler.slice());
+        let mut window_id = 0;
+        let mut watcher = self.watcher.take();
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                mapping = next_mapping(&mut watcher) => {
+                    self.on_mapping_reload(mapping);
+                    continue;
+                }
+            }
+
+            let traced_functions = self.traced_functions.lock().unwrap().clone();
+            let selected = scheduler.next_window(&traced_functions);
+
+            let program = breakpoint_program(&mut self.ebpf)?;
+            for link in links.drain(..) {
+                program.detach(link)?;
+            }
+
+            // the old breakpoints are detached before the window changes, so that no event is
+            // attributed to the wrong window
+            window_id += 1;
+            let mut windows = self.windows.lock().unwrap();
+            windows.insert(
+                window_id,
+                Window {
+                    id: window_id,
+                    functions: selected
+                        .iter()
+                        .map(|addr| traced_functions[addr].name.clone())
+                        .collect(),
+                },
+            );
+            if let Some(expired) = window_id.checked_sub(RETAINED_WINDOWS) {
+                windows.remove(&expired);
+            }
+            drop(windows);
+            let mut scheduler_window: Array<_, u64> =
+                Array::try_from(self.ebpf.map_mut("SchedulerWindow").expect("map exists"))?;
+            scheduler_window.set(0, window_id, 0)?;
+
+            let program = breakpoint_program(&mut self.ebpf)?;
+            let mut report = AttachReport::default();
+            for addr in selected {
+                let function = &traced_functions[&addr];
+                match attach_function(program, &self.threads, addr, function) {
+                    Ok(function_links) => {
+                        links.extend(function_links.into_iter().map(|(_, link)| link));
+                        report.attached.push(function.name.clone());
+                    }
+                    Err(failure) => report.failed.push((function.name.clone(), failure)),
+                }
+            }
+
+            // the functions that cannot be attached are skipped until their next window
+            if !report.is_complete() {
+                debug!("window {window_id}: {report}");
+                if let Some(window) = self.windows.lock().unwrap().get_mut(&window_id) {
+                    window.functions = report.attached;
+                }
+            }
+        }
+    }
+
+    /// Attaches uprobes to the functions of a module that is loaded with
+    /// `WasmRunner::load_precompiled`. Unlike the hardware breakpoints, there is no limit on
+    /// the number of uprobes, so any number of functions can be traced at once.
+    ///
+    /// `image_base` is the address that `cwasm_path` is mapped at, which is used to find the
+    /// file offsets of the traced addresses.
+    pub fn attach_uprobes<P: AsRef<Path>>(
+        &mut self,
+        cwasm_path: P,
+        image_base: u64,
+    ) -> anyhow::Result<()> {
+        let program: &mut UProbe = self
+            .ebpf
+            .program_mut("trace_function_call_uprobe")
+            .ok_or(anyhow!("missing program trace_function_call_uprobe"))?
+            .try_into()?;
+        program.load()?;
+
+        let traced_functions = self.traced_functions.lock().unwrap();
+        let addresses = traced_functions
+            .iter()
+            .flat_map(|(address, function)| [address].into_iter().chain(&function.exit_sites));
+        for address in addresses {
+            let offset = address - image_base;
+            debug!("attaching a uprobe at {address:x}, file offset {offset:x}");
+            program.attach(
+                UProbeAttachLocation::AbsoluteOffset(offset),
+                cwasm_path.as_ref(),
+                Some(self.pid as i32),
+                None,
+            )?;
+        }
+
         Ok(())
     }
 
-    pub async fn read_events(&mut self) -> anyhow::Result<()> {
-        let ring_buf = RingBuf::try_from(self.ebpf.take_map("FunctionCalls").unwrap())?;
+    /// Decodes the events in the background and passes them to `sink`, pairing the entries with
+    /// the exits of the functions whose exits are traced.
+    ///
+    /// The events are read until [`EventReader::stop`], which should be awaited before the sink
+    /// is finished.
+    pub async fn read_events(&mut self, sink: SharedSink) -> anyhow::Result<EventReader> {
+        let ring_buf = RingBuf::try_from(
+            self.ebpf
+                .take_map("FunctionCalls")
+                .ok_or(anyhow!("the events are already read"))?,
+        )?;
         let mut buf =
             tokio::io::unix::AsyncFd::with_interest(ring_buf, tokio::io::Interest::READABLE)?;
 
-        tokio::task::spawn(async move {
+        let mut decoder = EventDecoder {
+            traced_functions: self.traced_functions.clone(),
+            retired_functions: self.retired_functions.clone(),
+            windows: self.windows.clone(),
+            symbolizer: self.symbolizer.clone(),
+            sink,
+            call_tracker: CallTracker::default(),
+        };
+
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::task::spawn(async move {
             loop {
-                let mut guard = buf.readable_mut().await.unwrap();
-                {
-                    let item = guard.get_inner_mut().next().unwrap();
-                    let ptr = item.as_ptr() as *const FunctionCallEvent;
-                    let e = unsafe { *ptr };
-
-                    println!("data: {:?}", e.data);
-
-                    // let mut offset = 0;
-                    // loop {
-                    //     let str_len =
-                    //         u32::from_le_bytes(e.data[offset..offset + 4].try_into().expect("works"));
-                    //     if str_len == 0 {
-                    //         break;
-                    //     }
-                    //     offset += 4;
-                    //     let s = String::from_utf8_lossy(&e.data[offset..offset + str_len as usize]);
-                    //     offset += str_len as usize;
-                    //     println!("param: {s}");
-                    // }
+                tokio::select! {
+                    guard = buf.readable_mut() => {
+                        let mut guard = guard.unwrap();
+                        decoder.drain(guard.get_inner_mut());
+                        guard.clear_ready();
+                    }
+                    // the reader is also stopped when the `EventReader` is dropped
+                    _ = &mut stopped => {
+                        decoder.drain(buf.get_mut());
+                        decoder.finish();
+                        return;
+                    }
                 }
-                guard.clear_ready();
             }
         });
 
+        Ok(EventReader { stop, task })
+    }
+}
+
+/// The task that decodes the events, which is started by [`EbpfRunner::read_events`].
+pub struct EventReader {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl EventReader {
+    /// Reads the events that are left in the ring buffer and stops the task. The probes should be
+    /// detached before, so that no event is queued after the ring buffer is drained. The calls
+    /// whose exits are not seen by then, e.g. since they trap, are passed to the sink as their
+    /// entries.
+    pub async fn stop(self) -> anyhow::Result<()> {
+        // the task only ends early if it panics, which is returned by the join
+        let _ = self.stop.send(());
+        self.task.await?;
         Ok(())
     }
 }
+
+/// Decodes the records of the `FunctionCalls` ring buffer and passes them to the sink.
+struct EventDecoder {
+    traced_functions: Arc<Mutex<HashMap<u64, TracedFunction>>>,
+    retired_functions: Arc<Mutex<Vec<(u64, TracedFunction)>>>,
+    windows: Arc<Mutex<HashMap<u64, Window>>>,
+    symbolizer: Option<Arc<Mutex<Symbolizer>>>,
+    sink: SharedSink,
+    call_tracker: CallTracker,
+}
+
+impl EventDecoder {
+    /// Decodes the records until the ring buffer is empty.
+    fn drain(&mut self, ring_buf: &mut RingBuf<MapData>) {
+        // the functions that are retired by now have queued all their events, which are read
+        // before the ring buffer is empty
+        let retired = self.retired_functions.lock().unwrap().len();
+        while let Some(item) = ring_buf.next() {
+            self.on_record(&item);
+        }
+        self.retired_functions.lock().unwrap().drain(..retired);
+    }
+
+    fn on_record(&mut self, record: &[u8]) {
+        let (header, payload) = match event::read_record(record) {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("received an invalid record: {err}");
+                return;
+            }
+        };
+
+        let function = self
+            .traced_functions
+            .lock()
+            .unwrap()
+            .get(&header.addr)
+            .cloned()
+            .or_else(|| {
+                self.retired_functions
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .rev()
+                    .find(|(addr, _)| *addr == header.addr)
+                    .map(|(_, function)| function.clone())
+            });
+        let Some(function) = function else {
+            warn!(
+                "received an event for an untraced address: {:x}",
+                header.addr
+            );
+            return;
+        };
+
+        let mut event = match TraceEvent::decode(&header, payload, &function.name, &function.meta) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("failed to decode the event of {}: {err}", function.name);
+                return;
+            }
+        };
+
+        if let Some(window) = self.windows.lock().unwrap().get(&event.window) {
+            event.observed = window.functions.clone();
+        }
+
+        if let Some(symbolizer) = &self.symbolizer {
+            event.backtrace = symbolizer.lock().unwrap().symbolize_stack(&event.stack);
+        }
+
+        let res = if function.exit_sites.is_empty() {
+            self.sink.lock().unwrap().event(&event)
+        } else if let Some(call) = self.call_tracker.on_event(event) {
+            self.sink.lock().unwrap().call(&call)
+        } else {
+            Ok(())
+        };
+        if let Err(err) = res {
+            warn!("failed to write the event of {}: {err}", function.name);
+        }
+    }
+
+    /// Passes the entries of the calls that have not returned to the sink.
+    fn finish(&mut self) {
+        for entry in self.call_tracker.take_unfinished() {
+            if let Err(err) = self.sink.lock().unwrap().event(&entry) {
+                warn!("failed to write the event of {}: {err}", entry.name);
+            }
+        }
+    }
+}
+
+/// The program that the hardware breakpoints run.
+fn breakpoint_program(ebpf: &mut Ebpf) -> anyhow::Result<&mut PerfEvent> {
+    Ok(ebpf
+        .program_mut("trace_function_call")
+        .ok_or(anyhow!("missing program trace_function_call"))?
+        .try_into()?)
+}
+
+/// Attaches a breakpoint at `address` in the thread `tid`. A perf event that is opened for a pid
+/// only counts the thread with that id, and `inherit` does not cover the threads that already
+/// run, so every thread gets its own breakpoint.
+fn attach_breakpoint(
+    program: &mut PerfEvent,
+    tid: u32,
+    address: u64,
+) -> Result<PerfEventLinkId, ProgramError> {
+    program.attach(
+        PerfEventConfig::Breakpoint(BreakpointConfig::Instruction { address }),
+        PerfEventScope::OneProcess {
+            pid: tid,
+            cpu: None,
+        },
+        SamplePolicy::Period(1),
+        false,
+    )
+}
+
+/// Attaches the breakpoints of the entry and the exits of the function at `addr` in every thread
+/// of `threads`. The function is traced fully or not at all, so the breakpoints that are already
+/// attached are detached again if one of them fails. The threads that exit in the meantime are
+/// skipped.
+fn attach_function(
+    program: &mut PerfEvent,
+    threads: &[u32],
+    addr: u64,
+    function: &TracedFunction,
+) -> Result<Vec<ThreadLink>, AttachFailure> {
+    let mut links = Vec::new();
+    for tid in threads {
+        match attach_thread(program, *tid, addr, function) {
+            Ok(thread_links) => links.extend(thread_links),
+            Err(AttachFailure::NoThread) => {}
+            Err(failure) => {
+                detach_links(program, links, &function.name);
+                return Err(failure);
+            }
+        }
+    }
+
+    Ok(links)
+}
+
+/// Attaches the breakpoints of the function at `addr` in the thread `tid`, all of them or none.
+fn attach_thread(
+    program: &mut PerfEvent,
+    tid: u32,
+    addr: u64,
+    function: &TracedFunction,
+) -> Result<Vec<ThreadLink>, AttachFailure> {
+    let mut links = Vec::new();
+    for address in [addr]
+        .into_iter()
+        .chain(function.exit_sites.iter().copied())
+    {
+        match attach_breakpoint(program, tid, address) {
+            Ok(link) => {
+                debug!("attached a breakpoint at {address:#x} in thread {tid}");
+                links.push((tid, link));
+            }
+            Err(e) => {
+                detach_links(program, links, &function.name);
+                return Err(AttachFailure::from_error(&e));
+            }
+        }
+    }
+
+    Ok(links)
+}
+
+fn detach_links(program: &mut PerfEvent, links: Vec<ThreadLink>, name: &str) {
+    for (_, link) in links {
+        if let Err(e) = program.detach(link) {
+            warn!("failed to detach a breakpoint of {name}: {e}");
+        }
+    }
+}
+
+/// Waits for the next tick of the `watcher` and reloads the mapping, or never completes if there
+/// is no watcher.
+pub async fn next_mapping(watcher: &mut Option<MappingWatcher>) -> anyhow::Result<FunctionMapping> {
+    match watcher {
+        Some(watcher) => {
+            watcher.interval.tick().await;
+            (watcher.reload)()
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use wasm_tracer_abi::{
+    EVENT_HEADER_LEN, EVENT_VERSION, EventHeader, EventKind, FunctionMetadata, ParamType,
+};
+
+use crate::symbolize::Frame;
+
+/// A function call that is decoded from a `FunctionCalls` record.
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    /// Name of the function before mangling
+    pub name: String,
+    /// The address of the traced function
+    pub addr: u64,
+    /// The stack pointer at the time of the event, which is the same for the entry and the
+    /// exit of a single call
+    pub stack_pointer: u64,
+    pub kind: EventKind,
+    /// `CLOCK_MONOTONIC` at the time of the event in nanoseconds, which orders the events across
+    /// threads and CPUs
+    pub timestamp: u64,
+    /// The wall-clock time of the event, which is converted from `timestamp`
+    pub time: SystemTime,
+    pub tgid: u32,
+    pub tid: u32,
+    pub cpu: u32,
+    /// The scheduler window that the event is captured in, `0` if the breakpoints are not
+    /// scheduled
+    pub window: u64,
+    /// Names of the functions that are observed during the `window`, which is set by the
+    /// `EbpfRunner` and empty if the breakpoints are not scheduled
+    pub observed: Vec<String>,
+    /// The call stack, the traced function first and then the return addresses. Empty unless
+    /// the stacks are captured.
+    pub stack: Vec<u64>,
+    /// The symbolized `stack`, which is set by the `EbpfRunner`
+    pub backtrace: Vec<Frame>,
+    /// The params on [`EventKind::Entry`], and the return value (if its type is specified) on
+    /// [`EventKind::Exit`]
+    pub args: Vec<ArgValue>,
+    /// Set on [`EventKind::InvalidGuestPointer`]
+    pub invalid_pointer: Option<InvalidGuestPointer>,
+}
+
+/// A `Bytes` param that points outside of the linear memory of the guest, in which case none
+/// of the params are captured.
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidGuestPointer {
+    pub param_index: u32,
+    pub pointer: u64,
+    pub len: u64,
+    /// Size of the linear memory at the time of the call
+    pub memory_len: u64,
+}
+
+/// An entry that is paired with its exit.
+#[derive(Debug, Clone)]
+pub struct CompletedCall {
+    pub entry: TraceEvent,
+    pub ret: Option<ArgValue>,
+    /// The time between the entry and the exit
+    pub duration: Duration,
+}
+
+/// Pairs the entry and exit events of the same call.
+#[derive(Debug, Default)]
+pub struct CallTracker {
+    /// Entries that are waiting for their exits, keyed by `(addr, stack_pointer)`
+    pending: HashMap<(u64, u64), TraceEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ArgValue {
+    Signed(i64),
+    Unsigned(u64),
+    Bytes(Vec<u8>),
+    /// A value that is longer than the capture limit of its param
+    TruncatedBytes {
+        /// The first bytes of the value
+        captured: Vec<u8>,
+        /// The length of the whole value
+        len: u32,
+    },
+}
+
+/// Splits a raw `FunctionCalls` record into its header and payload, after checking that the
+/// record is written with the same layout.
+pub fn read_record(record: &[u8]) -> anyhow::Result<(EventHeader, &[u8])> {
+    if record.len() < EVENT_HEADER_LEN {
+        bail!("record is too short: {} bytes", record.len());
+    }
+
+    let header = unsafe { (record.as_ptr() as *const EventHeader).read_unaligned() };
+
+    if header.version != EVENT_VERSION {
+        bail!(
+            "unsupported record version {}, expected {EVENT_VERSION}",
+            header.version
+        );
+    }
+    if header.len as usize != record.len() {
+        bail!(
+            "record length mismatch: the header says {} bytes, got {}",
+            header.len,
+            record.len()
+        );
+    }
+
+    Ok((header, &record[EVENT_HEADER_LEN..]))
+}
+
+impl TraceEvent {
+    /// Decodes the params in `payload` by following the same layout that the eBPF program
+    /// uses while writing them, which is driven by `meta`.
+    pub fn decode(
+        header: &EventHeader,
+        payload: &[u8],
+        name: &str,
+        meta: &FunctionMetadata,
+    ) -> anyhow::Result<Self> {
+        if meta.param_count > meta.param_types.len() {
+            bail!("invalid param count {}", meta.param_count);
+        }
+
+        let kind =
+            EventKind::from_u8(header.kind).ok_or(anyhow!("invalid event kind {}", header.kind))?;
+
+        if kind == EventKind::Entry && header.param_count as usize != meta.param_count {
+            bail!(
+                "the record has {} params, expected {}",
+                header.param_count,
+                meta.param_count
+            );
+        }
+
+        let stack_len = header.stack_depth as usize * size_of::<u64>();
+        let Some(values_len) = payload.len().checked_sub(stack_len) else {
+            bail!("the record is too short for {} frames", header.stack_depth);
+        };
+        let (payload, stack) = payload.split_at(values_len);
+        let stack = stack
+            .chunks_exact(size_of::<u64>())
+            .map(|frame| u64::from_le_bytes(frame.try_into().expect("exact chunk; qed")))
+            .collect();
+
+        let mut reader = Reader { buf: payload };
+
+        let mut invalid_pointer = None;
+
+        let args = match kind {
+            EventKind::Entry => meta.param_types[..meta.param_count]
+                .iter()
+                .map(|ty| reader.read_arg(*ty))
+                .collect::<anyhow::Result<_>>()?,
+            EventKind::Exit => match meta.ret_type {
+                ParamType::Unspecified => Vec::new(),
+                ty => vec![reader.read_arg(ty)?],
+            },
+            EventKind::InvalidGuestPointer => {
+                invalid_pointer = Some(InvalidGuestPointer {
+                    param_index: u32::from_le_bytes(reader.take()?),
+                    pointer: u64::from_le_bytes(reader.take()?),
+                    len: u64::from_le_bytes(reader.take()?),
+                    memory_len: u64::from_le_bytes(reader.take()?),
+                });
+                Vec::new()
+            }
+        };
+
+        if !reader.buf.is_empty() {
+            bail!("{} trailing bytes in the record", reader.buf.len());
+        }
+
+        Ok(TraceEvent {
+            name: name.into(),
+            addr: header.addr,
+            stack_pointer: header.stack_pointer,
+            kind,
+            timestamp: header.timestamp,
+            time: monotonic_to_wall_clock(header.timestamp),
+            tgid: header.tgid,
+            tid: header.tid,
+            cpu: header.cpu,
+            window: header.window,
+            observed: Vec::new(),
+            stack,
+            backtrace: Vec::new(),
+            args,
+            invalid_pointer,
+        })
+    }
+}
+
+impl CallTracker {
+    /// Records an entry or completes the call that the exit belongs to. Returns `None` on
+    /// entries and on exits whose entries are not seen.
+    pub fn on_event(&mut self, event: TraceEvent) -> Option<CompletedCall> {
+        let key = (event.addr, event.stack_pointer);
+        match event.kind {
+            // the call still returns even if its params could not be captured
+            EventKind::Entry | EventKind::InvalidGuestPointer => {
+                let _ = self.pending.insert(key, event);
+                None
+            }
+            EventKind::Exit => {
+                let entry = self.pending.remove(&key)?;
+                Some(CompletedCall {
+                    duration: Duration::from_nanos(event.timestamp.saturating_sub(entry.timestamp)),
+                    entry,
+                    ret: event.args.into_iter().next(),
+                })
+            }
+        }
+    }
+
+    /// Removes the entries whose exits are not seen, in the order that they are called.
+    pub fn take_unfinished(&mut self) -> Vec<TraceEvent> {
+        let mut entries = self
+            .pending
+            .drain()
+            .map(|(_, entry)| entry)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.timestamp);
+        entries
+    }
+}
+
+/// The wall-clock time at `CLOCK_MONOTONIC` zero, which is sampled once so that all the events
+/// are converted with the same offset even if the wall clock is adjusted while tracing
+static MONOTONIC_EPOCH: LazyLock<SystemTime> = LazyLock::new(|| {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    assert_eq!(ret, 0, "CLOCK_MONOTONIC is always supported; qed");
+
+    SystemTime::now() - Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+});
+
+/// Converts a `bpf_ktime_get_ns` timestamp to the wall-clock time.
+pub fn monotonic_to_wall_clock(timestamp: u64) -> SystemTime {
+    *MONOTONIC_EPOCH + Duration::from_nanos(timestamp)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let bytes = self.take_slice(N)?;
+        Ok(bytes.try_into().expect("length is checked; qed"))
+    }
+
+    fn take_slice(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(anyhow!(
+                "event is too short: wanted {len} bytes, {} left",
+                self.buf.len()
+            ));
+        }
+
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn read_arg(&mut self, ty: ParamType) -> anyhow::Result<ArgValue> {
+        let value = match ty {
+            ParamType::I8 => ArgValue::Signed(i8::from_le_bytes(self.take()?) as i64),
+            ParamType::I32 => ArgValue::Signed(i32::from_le_bytes(self.take()?) as i64),
+            ParamType::I64 => ArgValue::Signed(i64::from_le_bytes(self.take()?)),
+            ParamType::U8 => ArgValue::Unsigned(u8::from_le_bytes(self.take()?) as u64),
+            ParamType::U32 => ArgValue::Unsigned(u32::from_le_bytes(self.take()?) as u64),
+            ParamType::U64 => ArgValue::Unsigned(u64::from_le_bytes(self.take()?)),
+            ParamType::Bytes => {
+                let len = u32::from_le_bytes(self.take()?);
+                let captured_len = u32::from_le_bytes(self.take()?);
+                let captured = self.take_slice(captured_len as usize)?.to_vec();
+                if captured_len < len {
+                    ArgValue::TruncatedBytes { captured, len }
+                } else {
+                    ArgValue::Bytes(captured)
+                }
+            }
+            ty @ (ParamType::F32 | ParamType::F64 | ParamType::Unspecified) => {
+                bail!("unsupported param type {ty:?}")
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+impl fmt::Display for ArgValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgValue::Signed(v) => write!(f, "{v}"),
+            ArgValue::Unsigned(v) => write!(f, "{v}"),
+            ArgValue::Bytes(bytes) => write_bytes(f, bytes),
+            ArgValue::TruncatedBytes { captured, len } => {
+                write_bytes(f, captured)?;
+                write!(f, "... ({len} bytes)")
+            }
+        }
+    }
+}
+
+fn write_bytes(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    match str::from_utf8(bytes) {
+        Ok(s) => write!(f, "{s:?}"),
+        // the value can be truncated in the middle of a character
+        Err(e) if e.error_len().is_none() => {
+            write!(
+                f,
+                "{:?}",
+                str::from_utf8(&bytes[..e.valid_up_to()]).expect("valid; qed")
+            )
+        }
+        Err(_) => {
+            f.write_str("0x")?;
+            bytes.iter().try_for_each(|b| write!(f, "{b:02x}"))
+        }
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_origin(f, self)?;
+        match self.kind {
+            EventKind::Entry | EventKind::InvalidGuestPointer => {
+                write!(f, "{}(", self.name)?;
+                write_params(f, self)?;
+                write!(f, ") @ {:x}", self.addr)?;
+            }
+            EventKind::Exit => {
+                write!(f, "{}(..) -> ", self.name)?;
+                write_args(f, &self.args)?;
+                write!(f, " @ {:x}", self.addr)?;
+            }
+        }
+        write_window(f, self.window, &self.observed)?;
+        write_backtrace(f, &self.backtrace)
+    }
+}
+
+impl fmt::Display for CompletedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_origin(f, &self.entry)?;
+        write!(f, "{}(", self.entry.name)?;
+        write_params(f, &self.entry)?;
+        f.write_str(") -> ")?;
+        match &self.ret {
+            Some(ret) => write!(f, "{ret}")?,
+            None => f.write_str("()")?,
+        }
+        write!(f, " @ {:x} in {:?}", self.entry.addr, self.duration)?;
+        write_window(f, self.entry.window, &self.entry.observed)?;
+        write_backtrace(f, &self.entry.backtrace)
+    }
+}
+
+fn write_args(f: &mut fmt::Formatter<'_>, args: &[ArgValue]) -> fmt::Result {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{arg}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for InvalidGuestPointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<invalid guest pointer: param {} is {:#x}..{:#x}, the memory is {:#x} bytes>",
+            self.param_index,
+            self.pointer,
+            self.pointer.saturating_add(self.len),
+            self.memory_len
+        )
+    }
+}
+
+fn write_params(f: &mut fmt::Formatter<'_>, entry: &TraceEvent) -> fmt::Result {
+    match &entry.invalid_pointer {
+        Some(invalid_pointer) => write!(f, "{invalid_pointer}"),
+        None => write_args(f, &entry.args),
+    }
+}
+
+/// Writes the time of the event as RFC 3339 in UTC, which is the format of `env_logger`, and the
+/// thread and the CPU that it's captured in.
+fn write_origin(f: &mut fmt::Formatter<'_>, event: &TraceEvent) -> fmt::Result {
+    let since_epoch = event.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    write!(
+        f,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:06}Z {}/{} cpu {} ",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros(),
+        event.tgid,
+        event.tid,
+        event.cpu
+    )
+}
+
+/// Converts the number of days since 1970-01-01 to `(year, month, day)`.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn write_window(f: &mut fmt::Formatter<'_>, window: u64, observed: &[String]) -> fmt::Result {
+    if observed.is_empty() {
+        return Ok(());
+    }
+    write!(f, " [window {window}: {}]", observed.join(", "))
+}
+
+fn write_backtrace(f: &mut fmt::Formatter<'_>, backtrace: &[Frame]) -> fmt::Result {
+    backtrace
+        .iter()
+        .try_for_each(|frame| write!(f, "\n    at {frame}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_at(time: SystemTime) -> TraceEvent {
+        TraceEvent {
+            name: "concat_str".into(),
+            addr: 0x7f00,
+            stack_pointer: 0,
+            kind: EventKind::Entry,
+            timestamp: 0,
+            time,
+            tgid: 10,
+            tid: 11,
+            cpu: 2,
+            window: 0,
+            observed: Vec::new(),
+            stack: Vec::new(),
+            backtrace: Vec::new(),
+            args: vec![ArgValue::Signed(42)],
+            invalid_pointer: None,
+        }
+    }
+
+    #[test]
+    fn civil_from_days_converts_the_epoch() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(31), (1970, 2, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn civil_from_days_handles_the_leap_years() {
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        // 2100 is not a leap year
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn origin_is_formatted_as_rfc_3339() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(
+            entry_at(time).to_string(),
+            "2023-11-14T22:13:20.123456Z 10/11 cpu 2 concat_str(42) @ 7f00"
+        );
+    }
+
+    #[test]
+    fn origin_pads_the_date_and_the_time() {
+        let time = UNIX_EPOCH + Duration::new(951_782_405, 7_000);
+        assert_eq!(
+            entry_at(time).to_string(),
+            "2000-02-29T00:00:05.000007Z 10/11 cpu 2 concat_str(42) @ 7f00"
+        );
+    }
+    #[test]
+    fn unfinished_calls_are_taken_in_order() {
+        let mut tracker = CallTracker::default();
+        for (stack_pointer, timestamp) in [(0x30, 300), (0x10, 100), (0x20, 200)] {
+            let mut entry = entry_at(UNIX_EPOCH);
+            entry.stack_pointer = stack_pointer;
+            entry.timestamp = timestamp;
+            assert!(tracker.on_event(entry).is_none());
+        }
+        let mut exit = entry_at(UNIX_EPOCH);
+        exit.kind = EventKind::Exit;
+        exit.stack_pointer = 0x20;
+        exit.timestamp = 250;
+        exit.args = Vec::new();
+        let call = tracker.on_event(exit).unwrap();
+        assert_eq!(call.duration, Duration::from_nanos(50));
+
+        let unfinished = tracker.take_unfinished();
+        let timestamps = unfinished
+            .iter()
+            .map(|entry| entry.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [100, 300]);
+        assert!(tracker.take_unfinished().is_empty());
+    }
+}
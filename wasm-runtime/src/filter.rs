@@ -0,0 +1,361 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use wasm_tracer_abi::{
+    Filter, FilterOp, FunctionFilters, FunctionMetadata, MAX_FILTER_PATTERN_LEN, MAX_FILTERS,
+    ParamType,
+};
+
+/// A predicate on a param of a function, e.g. `add_two_numbers arg1 > 100` or
+/// `handle_request arg0 starts_with GET`.
+///
+/// The ops are `==`, `!=`, `<`, `<=`, `>`, `>=` for the numeric params, and `==` and
+/// `starts_with` for the `&str` and `&[u8]` params. The value of a `&str` or `&[u8]` param is the
+/// rest of the expression, which is compared with the captured bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterSpec {
+    pub function: String,
+    pub param_index: usize,
+    pub op: FilterOp,
+    pub value: String,
+}
+
+impl FromStr for FilterSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow!("invalid filter `{s}`, expected `<FUNCTION> arg<N> <OP> <VALUE>`");
+
+        let (function, rest) = next_token(s).ok_or_else(invalid)?;
+        let (param, rest) = next_token(rest).ok_or_else(invalid)?;
+        let (op, value) = next_token(rest).ok_or_else(invalid)?;
+
+        let param_index = param
+            .strip_prefix("arg")
+            .and_then(|index| index.parse().ok())
+            .ok_or_else(invalid)?;
+
+        let op = match op {
+            "==" => FilterOp::Eq,
+            "!=" => FilterOp::Ne,
+            "<" => FilterOp::Lt,
+            "<=" => FilterOp::Le,
+            ">" => FilterOp::Gt,
+            ">=" => FilterOp::Ge,
+            "starts_with" => FilterOp::StartsWith,
+            _ => bail!("unknown op `{op}` in filter `{s}`"),
+        };
+
+        if value.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(FilterSpec {
+            function: function.into(),
+            param_index,
+            op,
+            value: value.into(),
+        })
+    }
+}
+
+fn next_token(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if s.is_empty() {
+        return None;
+    }
+    Some(match s.split_once(char::is_whitespace) {
+        Some((token, rest)) => (token, rest.trim()),
+        None => (s, ""),
+    })
+}
+
+/// Compiles the filters of every function against its signature, which checks that the params
+/// exist and that the ops and the values fit their types.
+pub fn compile_filters(
+    specs: &[FilterSpec],
+    function_abi: &HashMap<String, FunctionMetadata>,
+) -> anyhow::Result<HashMap<String, FunctionFilters>> {
+    let mut compiled: HashMap<String, (FunctionFilters, usize)> = HashMap::new();
+
+    for spec in specs {
+        let meta = function_abi
+            .get(&spec.function)
+            .ok_or(anyhow!("unknown function {} in a filter", spec.function))?;
+        if spec.param_index >= meta.param_count {
+            bail!(
+                "{} has {} params, the filter is on arg{}",
+                spec.function,
+                meta.param_count,
+                spec.param_index
+            );
+        }
+
+        let filter = compile_filter(spec, meta)?;
+
+        let (filters, len) = compiled
+            .entry(spec.function.clone())
+            .or_insert_with(|| (empty_filters(), 0));
+        if *len == MAX_FILTERS {
+            bail!("{} has more than {MAX_FILTERS} filters", spec.function);
+        }
+        filters.filters[*len] = filter;
+        *len += 1;
+    }
+
+    Ok(compiled
+        .into_iter()
+        .map(|(function, (filters, _))| (function, filters))
+        .collect())
+}
+
+fn compile_filter(spec: &FilterSpec, meta: &FunctionMetadata) -> anyhow::Result<Filter> {
+    let mut filter = empty_filter();
+    filter.param_index = spec.param_index as u8;
+
+    let ty = meta.param_types[spec.param_index];
+    let invalid_value = || {
+        anyhow!(
+            "invalid value `{}` for arg{} of {}, which is {ty:?}",
+            spec.value,
+            spec.param_index,
+            spec.function
+        )
+    };
+
+    match ty {
+        ParamType::Bytes => {
+            let op = match spec.op {
+                FilterOp::Eq => FilterOp::BytesEq,
+                FilterOp::StartsWith => FilterOp::StartsWith,
+                op => bail!(
+                    "{op:?} cannot be used on arg{} of {}",
+                    spec.param_index,
+                    spec.function
+                ),
+            };
+
+            let pattern = spec.value.as_bytes();
+            if pattern.len() > MAX_FILTER_PATTERN_LEN {
+                bail!(
+                    "the pattern `{}` is longer than {MAX_FILTER_PATTERN_LEN} bytes",
+                    spec.value
+                );
+            }
+            // the pattern is compared with the captured bytes, so it would never match
+            if pattern.len() > meta.capture_limits[spec.param_index] as usize {
+                bail!(
+                    "the pattern `{}` is longer than the capture limit of arg{} of {}",
+                    spec.value,
+                    spec.param_index,
+                    spec.function
+                );
+            }
+
+            filter.op = op as u8;
+            filter.pattern_len = pattern.len() as u8;
+            filter.pattern[..pattern.len()].copy_from_slice(pattern);
+        }
+        ParamType::I8 | ParamType::I32 | ParamType::I64 => {
+            let value: i64 = spec.value.parse().map_err(|_| invalid_value())?;
+            let fits = match ty {
+                ParamType::I8 => i8::try_from(value).is_ok(),
+                ParamType::I32 => i32::try_from(value).is_ok(),
+                _ => true,
+            };
+            if !fits || spec.op == FilterOp::StartsWith {
+                return Err(invalid_value());
+            }
+
+            filter.op = spec.op as u8;
+            filter.value = value as u64;
+        }
+        ParamType::U8 | ParamType::U32 | ParamType::U64 => {
+            let value: u64 = spec.value.parse().map_err(|_| invalid_value())?;
+            let fits = match ty {
+                ParamType::U8 => u8::try_from(value).is_ok(),
+                ParamType::U32 => u32::try_from(value).is_ok(),
+                _ => true,
+            };
+            if !fits || spec.op == FilterOp::StartsWith {
+                return Err(invalid_value());
+            }
+
+            filter.op = spec.op as u8;
+            filter.value = value;
+        }
+        ParamType::F32 | ParamType::F64 | ParamType::Unspecified => {
+            bail!(
+                "arg{} of {} is {ty:?}, which cannot be filtered",
+                spec.param_index,
+                spec.function
+            )
+        }
+    }
+
+    Ok(filter)
+}
+
+fn empty_filter() -> Filter {
+    Filter {
+        op: FilterOp::None as u8,
+        param_index: 0,
+        pattern_len: 0,
+        _padding: [0; 5],
+        value: 0,
+        pattern: [0; MAX_FILTER_PATTERN_LEN],
+    }
+}
+
+fn empty_filters() -> FunctionFilters {
+    FunctionFilters {
+        filters: [empty_filter(); MAX_FILTERS],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> FilterSpec {
+        s.parse().unwrap()
+    }
+
+    fn function_abi() -> HashMap<String, FunctionMetadata> {
+        HashMap::from([
+            (
+                "add".into(),
+                FunctionMetadata::new_fixed([ParamType::I8, ParamType::U32, ParamType::I64]),
+            ),
+            (
+                "handle_request".into(),
+                FunctionMetadata::new_fixed([ParamType::Bytes, ParamType::F64])
+                    .with_capture_limit(0, 4),
+            ),
+            (
+                "log".into(),
+                FunctionMetadata::new_fixed([ParamType::Bytes]),
+            ),
+        ])
+    }
+
+    fn compile(specs: &[&str]) -> anyhow::Result<HashMap<String, FunctionFilters>> {
+        let specs = specs.iter().map(|s| spec(s)).collect::<Vec<_>>();
+        compile_filters(&specs, &function_abi())
+    }
+
+    #[test]
+    fn parses_a_numeric_filter() {
+        assert_eq!(
+            spec("add_two_numbers arg1 >= 100"),
+            FilterSpec {
+                function: "add_two_numbers".into(),
+                param_index: 1,
+                op: FilterOp::Ge,
+                value: "100".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn value_is_the_rest_of_the_expression() {
+        let filter = spec("  handle_request   arg0 starts_with GET /index ");
+        assert_eq!(filter.op, FilterOp::StartsWith);
+        assert_eq!(filter.value, "GET /index");
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for s in [
+            "",
+            "add",
+            "add arg0",
+            "add arg0 ==",
+            "add 0 == 1",
+            "add argx == 1",
+            "add arg0 => 1",
+        ] {
+            assert!(s.parse::<FilterSpec>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn compiles_the_filters_of_every_function() {
+        let compiled = compile(&[
+            "add arg0 == -3",
+            "add arg1 < 7",
+            "handle_request arg0 starts_with GET",
+        ])
+        .unwrap();
+
+        let add = &compiled["add"].filters;
+        assert_eq!((add[0].op, add[0].param_index), (FilterOp::Eq as u8, 0));
+        assert_eq!(add[0].value as i64, -3);
+        assert_eq!((add[1].op, add[1].param_index), (FilterOp::Lt as u8, 1));
+        assert_eq!(add[1].value, 7);
+        assert_eq!(add[2].op, FilterOp::None as u8);
+
+        let request = &compiled["handle_request"].filters[0];
+        assert_eq!(request.op, FilterOp::StartsWith as u8);
+        assert_eq!(&request.pattern[..request.pattern_len as usize], b"GET");
+    }
+
+    #[test]
+    fn bytes_equality_compares_the_captured_bytes() {
+        let compiled = compile(&["handle_request arg0 == PUT"]).unwrap();
+        assert_eq!(
+            compiled["handle_request"].filters[0].op,
+            FilterOp::BytesEq as u8
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_functions_and_params() {
+        assert!(compile(&["sub arg0 == 1"]).is_err());
+        assert!(compile(&["add arg3 == 1"]).is_err());
+    }
+
+    #[test]
+    fn checks_that_the_value_fits_the_width() {
+        assert!(compile(&["add arg0 == 127"]).is_ok());
+        assert!(compile(&["add arg0 == 128"]).is_err());
+        assert!(compile(&["add arg1 == 4294967295"]).is_ok());
+        assert!(compile(&["add arg1 == 4294967296"]).is_err());
+        assert!(compile(&["add arg2 == 9223372036854775807"]).is_ok());
+        assert!(compile(&["add arg2 == 9223372036854775808"]).is_err());
+    }
+
+    #[test]
+    fn checks_the_sign() {
+        assert!(compile(&["add arg0 == -128"]).is_ok());
+        assert!(compile(&["add arg1 == -1"]).is_err());
+    }
+
+    #[test]
+    fn checks_the_op_against_the_type() {
+        assert!(compile(&["add arg1 starts_with 1"]).is_err());
+        assert!(compile(&["handle_request arg0 < GET"]).is_err());
+        assert!(compile(&["handle_request arg1 > 1"]).is_err());
+        assert!(compile(&["add arg1 == abc"]).is_err());
+    }
+
+    #[test]
+    fn pattern_must_fit_the_capture_limit() {
+        assert!(compile(&["handle_request arg0 == POST"]).is_ok());
+        assert!(compile(&["handle_request arg0 == PATCH"]).is_err());
+    }
+
+    #[test]
+    fn pattern_must_fit_the_filter() {
+        let pattern = "x".repeat(MAX_FILTER_PATTERN_LEN);
+        assert!(compile(&[&format!("log arg0 starts_with {pattern}")]).is_ok());
+        assert!(compile(&[&format!("log arg0 starts_with {pattern}x")]).is_err());
+    }
+
+    #[test]
+    fn rejects_more_than_max_filters() {
+        let filters = vec!["add arg1 > 1"; MAX_FILTERS + 1];
+        assert!(compile(&filters[..MAX_FILTERS]).is_ok());
+        assert!(compile(&filters).is_err());
+    }
+}
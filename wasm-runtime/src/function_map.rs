@@ -0,0 +1,141 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use aya::{
+    Pod,
+    maps::{MapData, MapError, PerCpuHashMap},
+};
+use wasm_tracer_abi::FunctionMetadata;
+
+use crate::ebpf_runner::TracedFunction;
+
+/// The userspace view of a per-CPU value that the eBPF program keeps for every function, which is
+/// merged over the CPUs and over the functions with the same name.
+pub trait MapSummary: Default + Clone {
+    type Value: Pod;
+
+    fn from_value(value: &Self::Value, meta: &FunctionMetadata) -> Self;
+
+    fn merge(&mut self, other: &Self);
+
+    /// Whether the function is in the reports, e.g. only the throttled functions have counters
+    fn includes(_meta: &FunctionMetadata) -> bool {
+        true
+    }
+}
+
+/// The summaries of the functions, keyed by the function name.
+#[derive(Debug)]
+pub struct FunctionReport<S> {
+    pub functions: BTreeMap<String, S>,
+}
+
+impl<S> Default for FunctionReport<S> {
+    fn default() -> Self {
+        Self {
+            functions: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S> FunctionReport<S> {
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+}
+
+/// Reads a per-CPU map of the eBPF program that is keyed by the function addresses. It's shared
+/// with the [`crate::ebpf_runner::EbpfRunner`], so it can be read after the runner is moved to its
+/// task.
+pub struct FunctionMapReader<S: MapSummary> {
+    map: Arc<Mutex<PerCpuHashMap<MapData, u64, S::Value>>>,
+    traced_functions: Arc<Mutex<HashMap<u64, TracedFunction>>>,
+    /// The summaries of the functions that are not traced anymore, keyed by the function name
+    retired: Arc<Mutex<HashMap<String, S>>>,
+}
+
+impl<S: MapSummary> Clone for FunctionMapReader<S> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            traced_functions: self.traced_functions.clone(),
+            retired: self.retired.clone(),
+        }
+    }
+}
+
+impl<S: MapSummary> FunctionMapReader<S> {
+    pub(crate) fn new(
+        map: PerCpuHashMap<MapData, u64, S::Value>,
+        traced_functions: Arc<Mutex<HashMap<u64, TracedFunction>>>,
+    ) -> Self {
+        Self {
+            map: Arc::new(Mutex::new(map)),
+            traced_functions,
+            retired: Default::default(),
+        }
+    }
+
+    /// Reads the summaries of the traced functions. The functions that are in more than one
+    /// module are merged, including the ones that are not traced anymore.
+    pub fn read(&self) -> anyhow::Result<FunctionReport<S>> {
+        let mut report = FunctionReport::default();
+        for (name, summary) in self.retired.lock().unwrap().iter() {
+            report.functions.insert(name.clone(), summary.clone());
+        }
+
+        let traced_functions = self.traced_functions.lock().unwrap().clone();
+        for (addr, function) in traced_functions {
+            if !S::includes(&function.meta) {
+                continue;
+            }
+            let summary = self.read_function(addr, &function.meta)?;
+            report
+                .functions
+                .entry(function.name)
+                .or_default()
+                .merge(&summary);
+        }
+
+        Ok(report)
+    }
+
+    /// Keeps the summary of the function at `addr` and removes its values, so that they are not
+    /// inherited by a function that is compiled to the same address later. This is called once
+    /// its probes are gone.
+    pub(crate) fn retire(&self, addr: u64, function: &TracedFunction) -> anyhow::Result<()> {
+        if !S::includes(&function.meta) {
+            return Ok(());
+        }
+
+        let summary = self.read_function(addr, &function.meta)?;
+        self.retired
+            .lock()
+            .unwrap()
+            .entry(function.name.clone())
+            .or_default()
+            .merge(&summary);
+
+        // the function has no values if it's never called
+        let _ = self.map.lock().unwrap().remove(&addr);
+
+        Ok(())
+    }
+
+    fn read_function(&self, addr: u64, meta: &FunctionMetadata) -> anyhow::Result<S> {
+        // the values are created on the first call of the function
+        let values = match self.map.lock().unwrap().get(&addr, 0) {
+            Ok(values) => values,
+            Err(MapError::KeyNotFound) => return Ok(S::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut summary = S::default();
+        for value in values.iter() {
+            summary.merge(&S::from_value(value, meta));
+        }
+        Ok(summary)
+    }
+}
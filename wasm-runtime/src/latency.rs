@@ -0,0 +1,179 @@
+use std::{fmt, time::Duration};
+
+use wasm_tracer_abi::{FunctionMetadata, LATENCY_BUCKETS, LatencyHistogram};
+
+use crate::function_map::{FunctionMapReader, FunctionReport, MapSummary};
+
+/// The latencies of the calls of a function, summed over every CPU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub calls: u64,
+    pub total_ns: u64,
+    pub max_ns: u64,
+    /// The log2 histogram of the latencies in nanoseconds, see [`LATENCY_BUCKETS`]
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl Default for LatencySummary {
+    fn default() -> Self {
+        Self {
+            calls: 0,
+            total_ns: 0,
+            max_ns: 0,
+            buckets: [0; LATENCY_BUCKETS],
+        }
+    }
+}
+
+impl LatencySummary {
+    pub fn mean(&self) -> Option<Duration> {
+        (self.calls > 0).then(|| Duration::from_nanos(self.total_ns / self.calls))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.calls > 0).then(|| Duration::from_nanos(self.max_ns))
+    }
+
+    /// Estimates the latency that `quantile` (`0.0..=1.0`) of the calls are not slower than.
+    ///
+    /// Only the bucket of a latency is known, so the latencies are assumed to be spread evenly
+    /// in their buckets, which is at most a factor of two off.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let total = self.buckets.iter().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((quantile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            if seen + count < rank {
+                seen += count;
+                continue;
+            }
+
+            let (low, high) = match bucket {
+                0 => (0, 0),
+                _ => (1u64 << (bucket - 1), 1u64 << bucket),
+            };
+            let within = (rank - seen) as f64 / *count as f64;
+            let ns = (low as f64 + (high - low) as f64 * within).min(self.max_ns as f64);
+            return Some(Duration::from_nanos(ns as u64));
+        }
+
+        self.max()
+    }
+}
+
+impl MapSummary for LatencySummary {
+    type Value = LatencyHistogram;
+
+    fn from_value(histogram: &LatencyHistogram, _meta: &FunctionMetadata) -> Self {
+        LatencySummary {
+            calls: histogram.calls,
+            total_ns: histogram.total_ns,
+            max_ns: histogram.max_ns,
+            buckets: histogram.buckets,
+        }
+    }
+
+    fn merge(&mut self, other: &LatencySummary) {
+        self.calls += other.calls;
+        self.total_ns += other.total_ns;
+        self.max_ns = self.max_ns.max(other.max_ns);
+        self.buckets
+            .iter_mut()
+            .zip(&other.buckets)
+            .for_each(|(count, other)| *count += other);
+    }
+}
+
+/// The [`LatencySummary`] of every traced function, keyed by the function name.
+pub type LatencyReport = FunctionReport<LatencySummary>;
+
+/// Reads the latencies of the functions from the `CallLatencies` map.
+pub type LatencyReader = FunctionMapReader<LatencySummary>;
+
+impl fmt::Display for LatencyReport {
+    /// Writes a table of the latency percentiles of every function.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duration = |d: Option<Duration>| match d {
+            Some(d) => format!("{d:.1?}"),
+            None => "-".to_string(),
+        };
+
+        write!(
+            f,
+            "{:<32} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "FUNCTION", "CALLS", "MEAN", "P50", "P90", "P99", "MAX"
+        )?;
+        for (name, summary) in &self.functions {
+            write!(
+                f,
+                "\n{name:<32} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                summary.calls,
+                duration(summary.mean()),
+                duration(summary.percentile(0.5)),
+                duration(summary.percentile(0.9)),
+                duration(summary.percentile(0.99)),
+                duration(summary.max())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(buckets: &[(usize, u64)], max_ns: u64) -> LatencySummary {
+        let mut summary = LatencySummary {
+            max_ns,
+            ..Default::default()
+        };
+        for (bucket, count) in buckets {
+            summary.buckets[*bucket] = *count;
+            summary.calls += count;
+        }
+        summary
+    }
+
+    #[test]
+    fn no_percentiles_without_calls() {
+        assert_eq!(summary(&[], 0).percentile(0.5), None);
+    }
+
+    #[test]
+    fn interpolates_within_a_bucket() {
+        // 4 calls in 8..16ns
+        let summary = summary(&[(4, 4)], 100);
+        assert_eq!(summary.percentile(0.25), Some(Duration::from_nanos(10)));
+        assert_eq!(summary.percentile(0.5), Some(Duration::from_nanos(12)));
+        assert_eq!(summary.percentile(1.0), Some(Duration::from_nanos(16)));
+    }
+
+    #[test]
+    fn interpolates_across_buckets() {
+        // 1 call in 1..2ns and 3 calls in 512..1024ns
+        let summary = summary(&[(1, 1), (10, 3)], 2000);
+        assert_eq!(summary.percentile(0.0), Some(Duration::from_nanos(2)));
+        assert_eq!(summary.percentile(0.5), Some(Duration::from_nanos(682)));
+        assert_eq!(summary.percentile(0.75), Some(Duration::from_nanos(853)));
+    }
+
+    #[test]
+    fn first_bucket_is_zero() {
+        let summary = summary(&[(0, 2)], 0);
+        assert_eq!(summary.percentile(0.9), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn clamps_to_the_max() {
+        // the slowest call is at the start of its bucket
+        let summary = summary(&[(4, 2)], 11);
+        assert_eq!(summary.percentile(0.5), Some(Duration::from_nanos(11)));
+        assert_eq!(summary.percentile(0.99), Some(Duration::from_nanos(11)));
+        assert_eq!(summary.max(), Some(Duration::from_nanos(11)));
+    }
+}
@@ -0,0 +1,26 @@
+//! Traces the calls of the functions of JIT-compiled wasm modules with eBPF, see [`Tracer`] to
+//! trace a module that is run by this process or by another process.
+
+pub mod aggregate;
+pub mod attach_report;
+pub mod chrome_trace;
+pub mod drops;
+pub mod dwarf;
+pub mod ebpf_runner;
+pub mod event;
+pub mod filter;
+pub mod function_map;
+pub mod latency;
+pub mod perf_util;
+pub mod proc_maps;
+pub mod profiler;
+pub mod scheduler;
+pub mod signature;
+pub mod sink;
+pub mod symbolize;
+pub mod throttle;
+pub mod tracer;
+pub mod vmctx;
+pub mod wasm_runner;
+
+pub use tracer::{AttachHandle, TraceHandle, Tracer};
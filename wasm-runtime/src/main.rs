@@ -1,34 +1,27 @@
-use aya::{
-    maps::{HashMap, RingBuf},
-    programs::{
-        PerfEvent,
-        perf_event::{BreakpointConfig, PerfEventConfig, PerfEventScope, SamplePolicy},
-    },
-};
-use log::{debug, warn};
+mod attach;
+mod profile;
+
+use std::time::Duration;
+
+use anyhow::anyhow;
 use tokio::signal;
-use wasm_tracer_abi::{FunctionMetadata, ParamType};
-use wasmtime::{
-    AsContextMut, Config, Engine, Linker, Memory, Module, ProfilingStrategy, Store,
-    StoreContextMut, TypedFunc,
+use wasm_tracer::{
+    Tracer, chrome_trace::ChromeTraceSink, scheduler::BreakpointScheduler, tracer::Compilation,
+    wasm_runner::WasmVM,
 };
 
-use crate::{
-    ebpf_runner::EbpfRunner,
-    perf_util::FunctionMapping,
-    wasm_runner::{WasmRunner, WasmVM},
-};
+use crate::{attach::AttachArgs, profile::ProfileArgs};
 
-pub mod ebpf_runner;
-pub mod perf_util;
-pub mod wasm_runner;
+/// The module that is traced unless `--module` is set, which is built from `wasm-binary` in the
+/// workspace
+const DEFAULT_MODULE: &str = "target/wasm32-unknown-unknown/release/wasm_binary.wasm";
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct FunctionCallEvent {
-    pub addr: u64,
-    pub data: [u8; 256],
-}
+const TRACED_FUNCTIONS: &[&str] = &[
+    "concat_str",
+    "add_two_numbers",
+    "trim_ascii_whitespace",
+    "collapse_ascii_spaces",
+];
 
 struct MyWasmVM;
 
@@ -44,55 +37,79 @@ impl WasmVM for MyWasmVM {
 async fn main() -> wasmtime::Result<()> {
     env_logger::init();
 
-    let mut wasm_runner = WasmRunner::<MyWasmVM>::load(
-        "/home/aeryz/dev/ebpf/wasmvm-tracing-poc/target/wasm32-unknown-unknown/release/wasm_binary.wasm",
-        (),
-    )?;
-
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("attach") {
+        return attach::run(AttachArgs::parse(&args[1..])?).await;
+    }
+    if args.first().map(String::as_str) == Some("profile") {
+        return profile::run(ProfileArgs::parse(&args[1..])?).await;
+    }
+
+    // uprobes are attached to a precompiled module, which lifts the limit of the hardware
+    // breakpoints, so every function with a known signature is traced
+    let use_uprobes = args.iter().any(|arg| arg == "--uprobe");
+    // the hardware breakpoints are rotated across every function with a known signature
+    let use_scheduler = args.iter().any(|arg| arg == "--schedule");
+    // the functions are read from the output of a profiler instead of the loaded module
+    let use_perf_map = args.iter().any(|arg| arg == "--perf-map");
+    let use_jitdump = args.iter().any(|arg| arg == "--jitdump");
+    // the exits are traced too, which takes a debug register per `ret` of every function
+    let trace_exits = args.iter().any(|arg| arg == "--exits");
+    // every event carries the call stack that led to it
+    let capture_stacks = args.iter().any(|arg| arg == "--stacks");
+    // the events are written as Chrome Trace Event JSON instead of being printed
+    let chrome_trace_path = args
+        .iter()
+        .position(|arg| arg == "--chrome-trace")
+        .map(|i| {
+            args.get(i + 1)
+                .ok_or(anyhow!("missing value for --chrome-trace"))
+        })
+        .transpose()?;
+    let wasm_path = args
+        .iter()
+        .position(|arg| arg == "--module")
+        .and_then(|i| args.get(i + 1))
+        .map_or(DEFAULT_MODULE, String::as_str);
+
+    let compilation = if use_uprobes {
+        Compilation::Precompiled(std::env::temp_dir().join("wasm_binary.cwasm"))
+    } else if use_perf_map {
+        Compilation::PerfMap
+    } else if use_jitdump {
+        Compilation::JitDump
+    } else {
+        Compilation::Jit
+    };
+
+    let mut tracer = Tracer::builder()
+        .module(wasm_path)
+        .bin_name("wasm_binary")
+        .compilation(compilation)
+        .exits(trace_exits)
+        .stacks(capture_stacks);
+
+    if use_scheduler {
+        tracer = tracer.schedule(BreakpointScheduler::round_robin(Duration::from_millis(10)));
+    } else if !use_uprobes {
+        for name in TRACED_FUNCTIONS {
+            tracer = tracer.trace_inferred(*name);
+        }
+    }
+
+    if let Some(path) = chrome_trace_path {
+        tracer = tracer.sink(ChromeTraceSink::create(path)?.shared());
+    }
+
+    let mut handle = tracer.start::<MyWasmVM>().await?;
+    if let Some(report) = handle.attach_report() {
+        println!("{report}");
+    }
+
+    let wasm_runner = &mut handle.wasm_runner;
     let x1 = wasm_runner.write_bytes(b"Hello, ")?;
     let y1 = wasm_runner.write_bytes(b"wasm!")?;
 
-    let function_mapping =
-        FunctionMapping::generate_from_perfmap_file_with_pid("wasm_binary", std::process::id())?;
-
-    let mem_base = wasm_runner.get_memory_base()?;
-
-    let mut ebpf_runner = EbpfRunner::load(
-        concat!(env!("OUT_DIR"), "/wasm-tracer-ebpf"),
-        mem_base,
-        [
-            (
-                "concat_str".to_string(),
-                FunctionMetadata::new_fixed([ParamType::Bytes, ParamType::Bytes]),
-            ),
-            (
-                "add_two_numbers".to_string(),
-                FunctionMetadata::new_fixed([ParamType::U32, ParamType::U32]),
-            ),
-            (
-                "trim_ascii_whitespace".to_string(),
-                FunctionMetadata::new_fixed([ParamType::Bytes]),
-            ),
-            (
-                "collapse_ascii_spaces".to_string(),
-                FunctionMetadata::new_fixed([ParamType::Bytes]),
-            ),
-            // (
-            //     "caesar_shift_ascii".to_string(),
-            //     FunctionMetadata::new_fixed([ParamType::Bytes, ParamType::I32]),
-            // ),
-        ]
-        .into_iter()
-        .collect(),
-        function_mapping,
-    )
-    .await?;
-
-    tokio::task::spawn(async move {
-        ebpf_runner.attach_multi().unwrap();
-        ebpf_runner.read_events().await.unwrap();
-    });
-
     tokio::task::spawn_blocking(|| {
         use std::io::{self, Read};
         let mut buf = [0u8; 1];
@@ -118,5 +135,11 @@ async fn main() -> wasmtime::Result<()> {
     ctrl_c.await?;
     println!("Exiting...");
 
+    let stats = handle.stop().await?;
+    if let Some(path) = chrome_trace_path {
+        println!("Wrote the trace to {path}");
+    }
+    println!("{stats}");
+
     Ok(())
 }
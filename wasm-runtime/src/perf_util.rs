@@ -1,7 +1,18 @@
-use std::{collections::HashMap, fs};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use iced_x86::{Decoder, DecoderOptions, Mnemonic};
+use log::debug;
+use wasmtime::Module;
 
 pub struct FunctionMapping {
-    addr_to_meta: HashMap<u64, FunctionMetadata>,
+    addr_to_meta: BTreeMap<u64, FunctionMetadata>,
 }
 
 pub struct FunctionMetadata {
@@ -13,18 +24,73 @@ pub struct FunctionMetadata {
     /// of the JIT-compiled wasm binary
     pub addr: u64,
     /// The size of the function
-    // TODO(aeryz): we might use this info to trace the ret instructions
-    // need to check if cranelift jmps to end and then rets or do rets
-    // at arbitrary locations tho.
     pub size: u64,
 }
 
+impl FunctionMetadata {
+    /// Disassembles the function in the process `pid` and returns the addresses of all of its
+    /// `ret` instructions.
+    ///
+    /// Cranelift is free to emit multiple epilogues, so every `ret` needs to be traced to catch
+    /// all the exits. Tail calls (`jmp` to another function) are not considered as exits.
+    pub fn return_sites(&self, pid: u32) -> io::Result<Vec<u64>> {
+        let mut code = vec![0; self.size as usize];
+        fs::File::open(format!("/proc/{pid}/mem"))?.read_exact_at(&mut code, self.addr)?;
+
+        Ok(Decoder::with_ip(64, &code, self.addr, DecoderOptions::NONE)
+            .into_iter()
+            .filter(|instruction| instruction.mnemonic() == Mnemonic::Ret)
+            .map(|instruction| instruction.ip())
+            .collect())
+    }
+}
+
+/// `JiTD` in little endian
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+const JITDUMP_HEADER_LEN: usize = 40;
+const JITDUMP_RECORD_HEADER_LEN: usize = 16;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_CLOSE: u32 = 3;
+
 impl FunctionMapping {
+    /// Reads the addresses of the functions directly from a loaded `module`, which doesn't
+    /// need any profiling strategy. The functions that are not in the name section are skipped.
+    ///
+    /// The names are demangled the same way as they are in the perf map.
+    pub fn from_module(module: &Module, bin_name: &str) -> Self {
+        let mut mapping = FunctionMapping {
+            addr_to_meta: BTreeMap::new(),
+        };
+
+        let text = module.text().as_ptr() as u64;
+
+        for function in module.functions() {
+            let Some(name) = function.name else {
+                continue;
+            };
+            let name = format!("{:#}", rustc_demangle::demangle(&name));
+
+            if name.starts_with(bin_name) {
+                mapping.insert(&name, text + function.offset as u64, function.len as u64);
+            }
+        }
+
+        mapping
+    }
+
     pub fn generate_from_perfmap_file_with_pid(bin_name: &str, pid: u32) -> wasmtime::Result<Self> {
-        let mut addr_to_meta = HashMap::new();
+        Self::generate_from_perfmap_file(format!("/tmp/perf-{pid}.map"), bin_name)
+    }
 
-        // TODO: make this configurable
-        let data = fs::read_to_string(format!("/tmp/perf-{pid}.map"))?;
+    pub fn generate_from_perfmap_file<P: AsRef<Path>>(
+        path: P,
+        bin_name: &str,
+    ) -> wasmtime::Result<Self> {
+        let mut mapping = FunctionMapping {
+            addr_to_meta: BTreeMap::new(),
+        };
+
+        let data = fs::read_to_string(path)?;
 
         for line in data.lines() {
             // Example: "7f3a1c400000 00000034 world"
@@ -34,34 +100,162 @@ impl FunctionMapping {
             let name = it.next();
 
             if let (Some(addr), Some(size), Some(name)) = (addr, size, name) {
-                println!("{} {} {}", addr, size, name);
+                debug!("{} {} {}", addr, size, name);
                 if name.starts_with(bin_name) {
                     let addr = u64::from_str_radix(addr.trim_start_matches("0x"), 16)?;
                     let size = u64::from_str_radix(size, 16)?;
 
-                    let maybe_name = name.split(":").last().unwrap_or(name);
-
-                    let _ = addr_to_meta.insert(
-                        addr,
-                        FunctionMetadata {
-                            name: maybe_name.into(),
-                            symbol: name.into(),
-                            addr,
-                            size,
-                        },
-                    );
+                    mapping.insert(name, addr, size);
+                }
+            }
+        }
+
+        Ok(mapping)
+    }
+
+    /// Reads the `JIT_CODE_LOAD` records of a jitdump file, which wasmtime writes to
+    /// `./jit-{pid}.dump` when `ProfilingStrategy::JitDump` is used.
+    ///
+    /// See the [jitdump specification](https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/jitdump-specification.txt)
+    pub fn from_jitdump<P: AsRef<Path>>(path: P, bin_name: &str) -> wasmtime::Result<Self> {
+        let mut mapping = FunctionMapping {
+            addr_to_meta: BTreeMap::new(),
+        };
+
+        let data = fs::read(path)?;
+
+        if data.len() < JITDUMP_HEADER_LEN || read_u32(&data, 0)? != JITDUMP_MAGIC {
+            bail!("not a little endian jitdump file");
+        }
+        let header_len = read_u32(&data, 8)? as usize;
+
+        let mut offset = header_len;
+        while offset + JITDUMP_RECORD_HEADER_LEN <= data.len() {
+            let id = read_u32(&data, offset)?;
+            let record_len = read_u32(&data, offset + 4)? as usize;
+            if record_len < JITDUMP_RECORD_HEADER_LEN || offset + record_len > data.len() {
+                bail!("truncated jitdump record at {offset}");
+            }
+            let record = &data[offset + JITDUMP_RECORD_HEADER_LEN..offset + record_len];
+
+            match id {
+                JIT_CODE_LOAD => {
+                    // pid: u32, tid: u32, vma: u64, code_addr: u64, code_size: u64,
+                    // code_index: u64, name: null-terminated string, code
+                    let code_addr = read_u64(record, 16)?;
+                    let code_size = read_u64(record, 24)?;
+                    let name = record
+                        .get(40..)
+                        .and_then(|tail| tail.split(|b| *b == 0).next())
+                        .ok_or(anyhow!("invalid code load record at {offset}"))?;
+                    let name = String::from_utf8_lossy(name);
+
+                    if name.starts_with(bin_name) {
+                        mapping.insert(&name, code_addr, code_size);
+                    }
                 }
+                JIT_CODE_CLOSE => break,
+                // moves, debug and unwinding info are not needed for the mapping
+                _ => {}
             }
+
+            offset += record_len;
         }
 
-        Ok(FunctionMapping { addr_to_meta })
+        Ok(mapping)
+    }
+
+    pub fn get(&self, addr: u64) -> Option<&FunctionMetadata> {
+        self.addr_to_meta.get(&addr)
+    }
+
+    /// Inserts a function, replacing the functions that overlap with it. The perf map and the
+    /// jitdump are append-only, so an overlapping entry means that the code memory of a dropped
+    /// module is reused.
+    fn insert(&mut self, symbol: &str, addr: u64, size: u64) {
+        // the functions in the mapping never overlap, so only the ones that start inside the new
+        // function and the last one that starts before it are checked
+        let overlapping = self
+            .addr_to_meta
+            .range(..addr + size)
+            .rev()
+            .take_while(|(start, meta)| **start >= addr || meta.addr + meta.size > addr)
+            .filter(|(_, meta)| meta.addr + meta.size > addr)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in overlapping {
+            self.addr_to_meta.remove(&start);
+        }
+
+        let _ = self.addr_to_meta.insert(
+            addr,
+            FunctionMetadata {
+                name: short_name(symbol).into(),
+                symbol: symbol.into(),
+                addr,
+                size,
+            },
+        );
+    }
+}
+
+/// How often the mapping of another process is reloaded to find the modules that are compiled
+/// or dropped after the tracing has started
+pub const MAPPING_RELOAD_PERIOD: Duration = Duration::from_secs(1);
+
+/// Where the addresses of the JIT-compiled functions of another process are read from.
+#[derive(Debug, Clone)]
+pub enum MappingSource {
+    /// A perf map, `/tmp/perf-{pid}.map` by default
+    PerfMap(PathBuf),
+    /// A jitdump that is written by wasmtime with `ProfilingStrategy::JitDump`
+    JitDump(PathBuf),
+}
+
+impl MappingSource {
+    /// The perf map that wasmtime writes with `ProfilingStrategy::PerfMap` in the process `pid`
+    pub fn perf_map(pid: u32) -> Self {
+        MappingSource::PerfMap(format!("/tmp/perf-{pid}.map").into())
+    }
+
+    pub fn read(&self, bin_name: &str) -> anyhow::Result<FunctionMapping> {
+        match self {
+            MappingSource::PerfMap(path) => {
+                FunctionMapping::generate_from_perfmap_file(path, bin_name)
+            }
+            MappingSource::JitDump(path) => FunctionMapping::from_jitdump(path, bin_name),
+        }
     }
 }
 
+fn read_u32(data: &[u8], offset: usize) -> wasmtime::Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(anyhow!("unexpected end of the jitdump at {offset}"))?;
+    Ok(u32::from_le_bytes(
+        bytes.try_into().expect("length is checked; qed"),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> wasmtime::Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or(anyhow!("unexpected end of the jitdump at {offset}"))?;
+    Ok(u64::from_le_bytes(
+        bytes.try_into().expect("length is checked; qed"),
+    ))
+}
+
+/// Strips the module path from a function symbol, e.g. `wasm_binary::concat_str` becomes
+/// `concat_str`.
+pub fn short_name(symbol: &str) -> &str {
+    symbol.split(":").last().unwrap_or(symbol)
+}
+
 impl<'a> IntoIterator for &'a FunctionMapping {
-    type Item = <&'a HashMap<u64, FunctionMetadata> as IntoIterator>::Item;
+    type Item = <&'a BTreeMap<u64, FunctionMetadata> as IntoIterator>::Item;
 
-    type IntoIter = <&'a HashMap<u64, FunctionMetadata> as IntoIterator>::IntoIter;
+    type IntoIter = <&'a BTreeMap<u64, FunctionMetadata> as IntoIterator>::IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
         (&self.addr_to_meta).into_iter()
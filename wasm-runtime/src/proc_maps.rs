@@ -0,0 +1,85 @@
+use std::{fs, ops::Range, path::PathBuf};
+
+struct Mapping {
+    start: u64,
+    end: u64,
+    perms: String,
+    offset: u64,
+    path: Option<PathBuf>,
+}
+
+/// An executable mapping of a file, e.g. the host binary or a shared library.
+#[derive(Debug, Clone)]
+pub struct FileMapping {
+    pub range: Range<u64>,
+    /// The offset in the file that `range.start` is mapped from
+    pub offset: u64,
+    pub path: PathBuf,
+}
+
+/// Returns the address ranges that are mapped as executable in the process `pid`. The code of a
+/// module is unmapped when the module is dropped, so this is used to find out which of the
+/// functions are gone.
+pub fn executable_ranges(pid: u32) -> anyhow::Result<Vec<Range<u64>>> {
+    Ok(read_mappings(pid)?
+        .into_iter()
+        .filter(|mapping| mapping.perms.get(2..3) == Some("x"))
+        .map(|mapping| mapping.start..mapping.end)
+        .collect())
+}
+
+/// Returns the executable mappings of the files in the process `pid`, which are used to
+/// symbolize the host frames of the call stacks.
+pub fn executable_file_mappings(pid: u32) -> anyhow::Result<Vec<FileMapping>> {
+    Ok(read_mappings(pid)?
+        .into_iter()
+        .filter(|mapping| mapping.perms.get(2..3) == Some("x"))
+        .filter_map(|mapping| {
+            Some(FileMapping {
+                range: mapping.start..mapping.end,
+                offset: mapping.offset,
+                path: mapping.path?,
+            })
+        })
+        .collect())
+}
+
+/// Returns the ids of the threads of the process `pid`. The hardware breakpoints are attached to
+/// every thread separately, since a breakpoint that is attached to `pid` only fires in the thread
+/// whose id is `pid`.
+pub fn threads(pid: u32) -> anyhow::Result<Vec<u32>> {
+    let mut threads = fs::read_dir(format!("/proc/{pid}/task"))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect::<Vec<u32>>();
+    threads.sort_unstable();
+    Ok(threads)
+}
+
+fn read_mappings(pid: u32) -> anyhow::Result<Vec<Mapping>> {
+    let data = fs::read_to_string(format!("/proc/{pid}/maps"))?;
+
+    let mut mappings = Vec::new();
+    for line in data.lines() {
+        // Example: "7f3a1c400000-7f3a1c410000 r-xp 00002000 08:01 1234 /usr/lib/libc.so.6"
+        let mut it = line.split_whitespace();
+        let (Some(range), Some(perms), Some(offset)) = (it.next(), it.next(), it.next()) else {
+            continue;
+        };
+        // the path is the rest of the line and can contain spaces, the anonymous mappings have
+        // either no path or a pseudo-path like `[heap]`
+        let path = line.find('/').map(|start| PathBuf::from(&line[start..]));
+        let Some((start, end)) = range.split_once('-') else {
+            continue;
+        };
+
+        mappings.push(Mapping {
+            start: u64::from_str_radix(start, 16)?,
+            end: u64::from_str_radix(end, 16)?,
+            perms: perms.into(),
+            offset: u64::from_str_radix(offset, 16)?,
+            path,
+        });
+    }
+
+    Ok(mappings)
+}
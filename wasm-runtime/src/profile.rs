@@ -0,0 +1,115 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{anyhow, bail};
+
+use wasm_tracer::{
+    perf_util::{MAPPING_RELOAD_PERIOD, MappingSource},
+    profiler::Profiler,
+};
+
+pub const USAGE: &str = "usage: wasm-trace profile --pid <PID> [--bin-name <NAME>] \
+[--perf-map <PATH> | --jitdump <PATH>] [--frequency <HZ>] [--stacks] [--folded <PATH>]";
+
+/// The sampling frequency unless it's configured otherwise, which is not a multiple of the
+/// common timer frequencies to not sample in lockstep with the periodic work
+const DEFAULT_FREQUENCY: u64 = 99;
+
+/// Arguments of `wasm-trace profile`, which samples the CPU usage of a running wasmtime process.
+#[derive(Debug)]
+pub struct ProfileArgs {
+    pub pid: u32,
+    /// The prefix of the functions in the perf map, every function is profiled if it's not set
+    pub bin_name: String,
+    pub mapping_source: MappingSource,
+    /// Samples per second on every CPU
+    pub frequency: u64,
+    /// Capture the call stack of every sample, which is needed for the folded stacks to show
+    /// more than the sampled function
+    pub capture_stacks: bool,
+    /// Where the folded stacks are written for flame graphs
+    pub folded: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut pid = None;
+        let mut bin_name = String::new();
+        let mut mapping_source = None;
+        let mut frequency = DEFAULT_FREQUENCY;
+        let mut capture_stacks = false;
+        let mut folded = None;
+
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            let mut value = || it.next().ok_or(anyhow!("missing value for {arg}\n{USAGE}"));
+            match arg.as_str() {
+                "--pid" => pid = Some(value()?.parse()?),
+                "--bin-name" => bin_name = value()?.clone(),
+                "--perf-map" => {
+                    mapping_source = Some(MappingSource::PerfMap(PathBuf::from(value()?)))
+                }
+                "--jitdump" => {
+                    mapping_source = Some(MappingSource::JitDump(PathBuf::from(value()?)))
+                }
+                "--frequency" => frequency = value()?.parse()?,
+                "--stacks" => capture_stacks = true,
+                "--folded" => folded = Some(PathBuf::from(value()?)),
+                _ => bail!("unknown argument {arg}\n{USAGE}"),
+            }
+        }
+
+        let pid = pid.ok_or(anyhow!("--pid is required\n{USAGE}"))?;
+        if frequency == 0 {
+            bail!("--frequency must be positive\n{USAGE}");
+        }
+
+        let mapping_source = mapping_source.unwrap_or_else(|| MappingSource::perf_map(pid));
+
+        Ok(ProfileArgs {
+            pid,
+            bin_name,
+            mapping_source,
+            frequency,
+            capture_stacks,
+            folded,
+        })
+    }
+}
+
+/// Samples the process `args.pid` until Ctrl-C, then prints the histogram of the wasm functions
+/// and writes the folded stacks.
+pub async fn run(args: ProfileArgs) -> anyhow::Result<()> {
+    let function_mapping = args.mapping_source.read(&args.bin_name)?;
+
+    let mut profiler = Profiler::load(
+        concat!(env!("OUT_DIR"), "/wasm-tracer-ebpf"),
+        args.pid,
+        &function_mapping,
+        args.capture_stacks,
+    )?;
+
+    let mapping_source = args.mapping_source.clone();
+    let bin_name = args.bin_name.clone();
+    profiler.set_mapping_watcher(MAPPING_RELOAD_PERIOD, move || {
+        mapping_source.read(&bin_name)
+    });
+
+    println!(
+        "Profiling {} at {} Hz, waiting for Ctrl-C...",
+        args.pid, args.frequency
+    );
+    let profile = profiler
+        .run(args.frequency, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    println!("{profile}");
+
+    if let Some(path) = &args.folded {
+        profile.write_folded(fs::File::create(path)?)?;
+        println!("Wrote the folded stacks to {}", path.display());
+    }
+
+    Ok(())
+}
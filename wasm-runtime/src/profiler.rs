@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use aya::{
+    Ebpf,
+    maps::RingBuf,
+    programs::{
+        PerfEvent,
+        perf_event::{PerfEventConfig, PerfEventScope, SamplePolicy, SoftwareEvent},
+    },
+    util::online_cpus,
+};
+use log::warn;
+use wasm_tracer_abi::{SAMPLE_HEADER_LEN, SampleHeader};
+
+use crate::{
+    ebpf_runner::{MappingWatcher, next_mapping},
+    perf_util::FunctionMapping,
+    symbolize::{Frame, Symbolizer},
+};
+
+/// Samples the CPU usage of a process on a clock and attributes the samples to the
+/// JIT-compiled wasm functions. Unlike the breakpoints, this has no limit on the number of
+/// functions that are observed.
+pub struct Profiler {
+    ebpf: Ebpf,
+    symbolizer: Symbolizer,
+    watcher: Option<MappingWatcher>,
+    profile: Profile,
+}
+
+/// The samples that are aggregated by [`Profiler::run`].
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub samples: u64,
+    /// Samples whose instruction pointer is in a wasm function, keyed by the function name
+    pub functions: HashMap<String, u64>,
+    /// Samples whose instruction pointer is outside of the JIT code, e.g. in the runtime or in
+    /// a host function
+    pub host_samples: u64,
+    /// Samples per call stack, the outermost frame first and the frames separated by `;`. Only
+    /// the sampled function is in the stack unless the stacks are captured.
+    pub folded: HashMap<String, u64>,
+}
+
+impl Profiler {
+    /// Loads the eBPF program to sample the process `pid`. The whole user stack is captured on
+    /// every sample if `capture_stacks` is set, otherwise only the sampled function is known.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        pid: u32,
+        mapping: &FunctionMapping,
+        capture_stacks: bool,
+    ) -> anyhow::Result<Self> {
+        let ebpf = aya::EbpfLoader::new()
+            .override_global("SAMPLED_TGID", &pid, true)
+            .override_global("CAPTURE_STACKS", &(capture_stacks as u8), true)
+            .load(&fs::read(path)?)?;
+
+        let mut symbolizer = Symbolizer::new(pid);
+        symbolizer.set_functions(mapping);
+
+        Ok(Self {
+            ebpf,
+            symbolizer,
+            watcher: None,
+            profile: Profile::default(),
+        })
+    }
+
+    /// Reloads the function mapping with `reload` every `period` while `run` is running, so the
+    /// modules that are compiled after the profiling has started are symbolized too.
+    pub fn set_mapping_watcher(
+        &mut self,
+        period: Duration,
+        reload: impl FnMut() -> anyhow::Result<FunctionMapping> + Send + 'static,
+    ) {
+        self.watcher = Some(MappingWatcher::new(period, reload));
+    }
+
+    /// Samples every CPU `frequency` times per second until `stop` completes, and returns the
+    /// aggregated samples.
+    pub async fn run(
+        mut self,
+        frequency: u64,
+        stop: impl Future<Output = ()>,
+    ) -> anyhow::Result<Profile> {
+        let program: &mut PerfEvent = self.ebpf.program_mut("sample_cpu").unwrap().try_into()?;
+        program.load()?;
+
+        // the links are detached when the program is dropped
+        let cpus =
+            online_cpus().map_err(|(_, e)| anyhow!("failed to read the online CPUs: {e}"))?;
+        for cpu in cpus {
+            program.attach(
+                PerfEventConfig::Software(SoftwareEvent::CpuClock),
+                PerfEventScope::AllProcessesOneCpu { cpu },
+                SamplePolicy::Frequency(frequency),
+                false,
+            )?;
+        }
+
+        let ring_buf = RingBuf::try_from(self.ebpf.take_map("Samples").unwrap())?;
+        let mut buf =
+            tokio::io::unix::AsyncFd::with_interest(ring_buf, tokio::io::Interest::READABLE)?;
+
+        let mut watcher = self.watcher.take();
+        tokio::pin!(stop);
+
+        loop {
+            tokio::select! {
+                guard = buf.readable_mut() => {
+                    let mut guard = guard?;
+                    while let Some(item) = guard.get_inner_mut().next() {
+                        match read_sample(&item) {
+                            Ok(stack) => {
+                                let frames = self.symbolizer.symbolize_stack(&stack);
+                                self.profile.add(&frames);
+                            }
+                            Err(e) => warn!("received an invalid sample: {e}"),
+                        }
+                    }
+                    guard.clear_ready();
+                }
+                mapping = next_mapping(&mut watcher) => match mapping {
+                    Ok(mapping) => self.symbolizer.set_functions(&mapping),
+                    // the mapping can be read while it's being written, so it's retried on the
+                    // next tick
+                    Err(e) => warn!("failed to update the function mapping: {e}"),
+                },
+                _ = &mut stop => break,
+            }
+        }
+
+        Ok(self.profile)
+    }
+}
+
+/// Returns the frames of a raw `Samples` record.
+fn read_sample(record: &[u8]) -> anyhow::Result<Vec<u64>> {
+    if record.len() < SAMPLE_HEADER_LEN {
+        bail!("record is too short: {} bytes", record.len());
+    }
+
+    let header = unsafe { (record.as_ptr() as *const SampleHeader).read_unaligned() };
+
+    let frames = &record[SAMPLE_HEADER_LEN..];
+    if frames.len() != header.stack_depth as usize * size_of::<u64>() {
+        bail!(
+            "record length mismatch: {} frames in {} bytes",
+            header.stack_depth,
+            frames.len()
+        );
+    }
+
+    Ok(frames
+        .chunks_exact(size_of::<u64>())
+        .map(|frame| u64::from_le_bytes(frame.try_into().expect("exact chunk; qed")))
+        .collect())
+}
+
+impl Profile {
+    /// Adds a sample whose innermost frame is first.
+    pub fn add(&mut self, frames: &[Frame]) {
+        let Some(leaf) = frames.first() else {
+            return;
+        };
+
+        self.samples += 1;
+        match leaf {
+            Frame::Wasm { name, .. } => *self.functions.entry(name.clone()).or_default() += 1,
+            _ => self.host_samples += 1,
+        }
+
+        let stack = frames
+            .iter()
+            .rev()
+            .map(Frame::function_name)
+            .collect::<Vec<_>>()
+            .join(";");
+        *self.folded.entry(stack).or_default() += 1;
+    }
+
+    /// Writes the folded stacks in the format of `flamegraph.pl` and `inferno`, one stack and
+    /// its sample count per line.
+    pub fn write_folded(&self, mut w: impl Write) -> io::Result<()> {
+        let mut folded = self.folded.iter().collect::<Vec<_>>();
+        folded.sort();
+        for (stack, count) in folded {
+            writeln!(w, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Profile {
+    /// Writes the histogram of the wasm functions, the most sampled first.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |count: u64| count as f64 * 100.0 / self.samples.max(1) as f64;
+
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        writeln!(f, "{} samples", self.samples)?;
+        for (name, count) in functions {
+            writeln!(f, "{count:>10} {:>6.2}% {name}", percent(*count))?;
+        }
+        write!(
+            f,
+            "{:>10} {:>6.2}% [outside of wasm]",
+            self.host_samples,
+            percent(self.host_samples)
+        )
+    }
+}
@@ -0,0 +1,255 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::ebpf_runner::TracedFunction;
+
+/// The number of debug registers on x86_64
+pub const HARDWARE_BREAKPOINT_SLOTS: usize = 4;
+
+/// Rotates a limited number of hardware breakpoint slots across a larger set of functions in
+/// time slices.
+///
+/// The functions are picked with the smooth weighted round-robin algorithm, so in the long run
+/// every function is observed in a share of the windows that is proportional to its weight. The
+/// share of a function is at most every window, and a function whose weight would give it more
+/// than that is observed in every window while the rest share the other slots.
+pub struct BreakpointScheduler {
+    /// Number of breakpoints that can be attached at the same time
+    slots: usize,
+    /// Duration of a single window
+    slice: Duration,
+    /// Functions that are not present here have the weight `1`
+    weights: HashMap<String, u32>,
+    /// Current credit of every function, keyed by the function address
+    credits: HashMap<u64, i64>,
+}
+
+/// A time slice during which a fixed set of functions is observed.
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub id: u64,
+    /// Names of the functions that have breakpoints attached during this window
+    pub functions: Vec<String>,
+}
+
+impl BreakpointScheduler {
+    /// Gives every function the same share of the slots.
+    pub fn round_robin(slice: Duration) -> Self {
+        Self::weighted(slice, HashMap::new())
+    }
+
+    /// Gives every function a share of the slots that is proportional to its weight. Functions
+    /// that are not in `weights` have the weight `1`.
+    pub fn weighted(slice: Duration, weights: HashMap<String, u32>) -> Self {
+        BreakpointScheduler {
+            slots: HARDWARE_BREAKPOINT_SLOTS,
+            slice,
+            weights,
+            credits: HashMap::new(),
+        }
+    }
+
+    pub fn with_slots(mut self, slots: usize) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    pub fn slice(&self) -> Duration {
+        self.slice
+    }
+
+    /// Picks the functions to observe in the next window. A function occupies one slot for its
+    /// entry and one for every exit site.
+    pub fn next_window(&mut self, functions: &HashMap<u64, TracedFunction>) -> Vec<u64> {
+        let weight_of = |function: &TracedFunction| {
+            self.weights.get(&function.name).copied().unwrap_or(1) as i64
+        };
+
+        let mut candidates = functions
+            .iter()
+            .filter(|(_, function)| {
+                function.exit_sites.len() < self.slots && weight_of(function) > 0
+            })
+            .map(|(addr, function)| (*addr, function, weight_of(function)))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Vec::new();
+        }
+
+        let total_weight = candidates.iter().map(|(_, _, weight)| weight).sum::<i64>();
+
+        // highest credit first, the address is used for a deterministic order
+        candidates.sort_by_key(|(addr, _, _)| {
+            (-self.credits.get(addr).copied().unwrap_or_default(), *addr)
+        });
+
+        let mut free_slots = self.slots;
+        let mut selected = Vec::new();
+        for (addr, function, _) in &candidates {
+            let needed = 1 + function.exit_sites.len();
+            if needed > free_slots {
+                continue;
+            }
+            free_slots -= needed;
+            selected.push(*addr);
+        }
+
+        // every function earns its weight for every function that is picked, and a picked
+        // function pays the total weight, so the credits sum to zero and a function is picked in
+        // a share of the windows that is proportional to its weight
+        let picked = selected.len() as i64;
+        for (addr, _, weight) in &candidates {
+            *self.credits.entry(*addr).or_default() += picked * weight;
+        }
+        for addr in &selected {
+            let credit = self
+                .credits
+                .get_mut(addr)
+                .expect("credit is set above; qed");
+            // a function that is picked in every window cannot use more, so it does not save up
+            // the credit that it would take from the others
+            *credit = (*credit - total_weight).min(total_weight);
+        }
+        // the capped credits are taken from the others evenly, which keeps them bounded
+        let mean = candidates
+            .iter()
+            .map(|(addr, _, _)| self.credits[addr])
+            .sum::<i64>()
+            / candidates.len() as i64;
+        for (addr, _, _) in &candidates {
+            *self
+                .credits
+                .get_mut(addr)
+                .expect("credit is set above; qed") -= mean;
+        }
+
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_tracer_abi::FunctionMetadata;
+
+    use super::*;
+
+    /// Functions named `f0`, `f1`, ... with the given number of exit sites
+    fn functions(exit_sites: &[usize]) -> HashMap<u64, TracedFunction> {
+        exit_sites
+            .iter()
+            .enumerate()
+            .map(|(i, exit_sites)| {
+                let addr = 0x1000 * (i as u64 + 1);
+                let function = TracedFunction {
+                    name: format!("f{i}"),
+                    meta: FunctionMetadata::new_fixed([]),
+                    exit_sites: (1..=*exit_sites as u64).map(|n| addr + n).collect(),
+                };
+                (addr, function)
+            })
+            .collect()
+    }
+
+    /// The number of windows that every function is picked in, in the order of `functions`
+    fn picks(
+        scheduler: &mut BreakpointScheduler,
+        functions: &HashMap<u64, TracedFunction>,
+        windows: usize,
+    ) -> Vec<usize> {
+        let mut picks = vec![0; functions.len()];
+        for _ in 0..windows {
+            for addr in scheduler.next_window(functions) {
+                picks[(addr / 0x1000 - 1) as usize] += 1;
+            }
+        }
+        picks
+    }
+
+    fn weights(weights: &[u32]) -> HashMap<String, u32> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| (format!("f{i}"), *weight))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_shares_the_slots_evenly() {
+        let functions = functions(&[0; 6]);
+        let mut scheduler = BreakpointScheduler::round_robin(Duration::from_millis(10));
+        assert_eq!(picks(&mut scheduler, &functions, 300), vec![200; 6]);
+    }
+
+    #[test]
+    fn share_is_proportional_to_the_weight() {
+        // 4 slots and a total weight of 9, so f0 is picked in 8 of 9 windows and the rest in 4
+        let functions = functions(&[0; 8]);
+        let mut scheduler = BreakpointScheduler::weighted(
+            Duration::from_millis(10),
+            weights(&[2, 1, 1, 1, 1, 1, 1, 1]),
+        );
+        assert_eq!(
+            picks(&mut scheduler, &functions, 900),
+            vec![800, 400, 400, 400, 400, 400, 400, 400]
+        );
+    }
+
+    #[test]
+    fn heavy_function_is_picked_in_every_window() {
+        let functions = functions(&[0; 6]);
+        let mut scheduler =
+            BreakpointScheduler::weighted(Duration::from_millis(10), weights(&[10, 1, 1, 1, 1, 1]));
+        assert_eq!(
+            picks(&mut scheduler, &functions, 500),
+            vec![500, 300, 300, 300, 300, 300]
+        );
+    }
+
+    #[test]
+    fn credits_stay_bounded() {
+        let functions = functions(&[0; 6]);
+        let mut scheduler =
+            BreakpointScheduler::weighted(Duration::from_millis(10), weights(&[10, 1, 1, 1, 1, 1]));
+        picks(&mut scheduler, &functions, 10_000);
+        assert!(
+            scheduler
+                .credits
+                .values()
+                .all(|credit| credit.abs() <= 4 * 15)
+        );
+    }
+
+    #[test]
+    fn functions_that_do_not_fit_are_never_picked() {
+        // f1 needs 5 slots, and f0 needs 3 so it's never picked with both f2 and f3
+        let functions = functions(&[2, 4, 0, 0]);
+        let mut scheduler = BreakpointScheduler::round_robin(Duration::from_millis(10));
+        let picks = picks(&mut scheduler, &functions, 100);
+        assert_eq!(picks[1], 0);
+        assert!(picks[0] > 0 && picks[2] > 0 && picks[3] > 0);
+    }
+
+    #[test]
+    fn zero_weight_is_never_picked() {
+        let functions = functions(&[0; 5]);
+        let mut scheduler =
+            BreakpointScheduler::weighted(Duration::from_millis(10), weights(&[0, 1, 1, 1, 1]));
+        assert_eq!(
+            picks(&mut scheduler, &functions, 10),
+            vec![0, 10, 10, 10, 10]
+        );
+    }
+
+    #[test]
+    fn slots_are_never_overbooked() {
+        let functions = functions(&[2, 1, 0, 0, 3, 0]);
+        let mut scheduler = BreakpointScheduler::round_robin(Duration::from_millis(10));
+        for _ in 0..100 {
+            let used = scheduler
+                .next_window(&functions)
+                .iter()
+                .map(|addr| 1 + functions[addr].exit_sites.len())
+                .sum::<usize>();
+            assert!(used <= HARDWARE_BREAKPOINT_SLOTS);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use log::debug;
+use wasm_tracer_abi::{DEFAULT_CAPTURE_LIMIT, FunctionMetadata, ParamType};
+use wasmparser::{KnownCustom, Name, Parser, Payload, TypeRef, ValType};
+
+use crate::{
+    dwarf,
+    perf_util::{FunctionMapping, short_name},
+    vmctx,
+};
+
+/// Signatures of the named functions in a module, which are the core wasm signatures unless
+/// they are annotated.
+pub struct ModuleSignatures {
+    by_name: HashMap<String, Signature>,
+    /// See [`vmctx::memory_definition_offset`]
+    memory_offset: u32,
+    /// The capture limit of the [`ParamType::Bytes`] params that are not in `capture_limits`
+    capture_limit: u16,
+    /// Capture limits of specific params, keyed by the function name and the param index
+    capture_limits: HashMap<(String, usize), u16>,
+    /// Every how many calls of a function are traced, keyed by the function name
+    sampling: HashMap<String, u32>,
+    /// `(calls per second, burst)` of the rate limited functions, keyed by the function name
+    rate_limits: HashMap<String, (u32, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub params: Vec<ParamType>,
+    pub ret_type: ParamType,
+}
+
+impl Signature {
+    /// Returns `None` if the function has more params than the eBPF program can parse.
+    pub fn metadata(&self) -> Option<FunctionMetadata> {
+        FunctionMetadata::new(&self.params)
+            .ok()
+            .map(|meta| meta.with_ret(self.ret_type))
+    }
+
+    /// Lowers the signature to the core wasm types, e.g. [`ParamType::Bytes`] becomes two
+    /// [`ParamType::I32`]s.
+    fn core_params(&self) -> Vec<ParamType> {
+        self.params
+            .iter()
+            .flat_map(|ty| match ty {
+                ParamType::Bytes => vec![ParamType::I32, ParamType::I32],
+                ty => vec![core_type(*ty)],
+            })
+            .collect()
+    }
+
+    /// Whether the signature lowers to the `core` signature of the function.
+    fn lowers_to(&self, core: &Signature) -> bool {
+        let core_params = self.core_params();
+        core.params.len() == core_params.len()
+            && core
+                .params
+                .iter()
+                .zip(core_params)
+                .all(|(a, b)| *a as u8 == b as u8)
+            && core.ret_type as u8 == core_type(self.ret_type) as u8
+    }
+}
+
+impl ModuleSignatures {
+    /// Reads the signatures of all the functions that are named in the name section of `wasm`.
+    ///
+    /// Functions that use non-numeric types are skipped since they cannot be traced.
+    pub fn parse(wasm: &[u8]) -> anyhow::Result<Self> {
+        let mut types = Vec::new();
+        // type index of every function, including the imported ones
+        let mut func_types = Vec::new();
+        let mut names = Vec::new();
+
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::TypeSection(reader) => {
+                    for ty in reader.into_iter_err_on_gc_types() {
+                        types.push(ty?);
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader.into_imports() {
+                        if let TypeRef::Func(ty) | TypeRef::FuncExact(ty) = import?.ty {
+                            func_types.push(ty);
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        func_types.push(ty?);
+                    }
+                }
+                Payload::CustomSection(reader) => {
+                    let KnownCustom::Name(reader) = reader.as_known() else {
+                        continue;
+                    };
+                    for name in reader {
+                        if let Name::Function(map) = name? {
+                            for naming in map {
+                                let naming = naming?;
+                                names.push((naming.index, naming.name.to_string()));
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut by_name = HashMap::new();
+
+        for (func_index, name) in names {
+            let ty = func_types
+                .get(func_index as usize)
+                .and_then(|ty| types.get(*ty as usize))
+                .ok_or(anyhow!("no type for the function {name}"))?;
+
+            let params = ty
+                .params()
+                .iter()
+                .map(|ty| param_type(*ty))
+                .collect::<Option<Vec<_>>>();
+            let ret = match ty.results() {
+                [] => Some(ParamType::Unspecified),
+                [ty] => param_type(*ty),
+                _ => None,
+            };
+
+            let (Some(params), Some(ret)) = (params, ret) else {
+                debug!("skipping {name} since its signature has unsupported types");
+                continue;
+            };
+
+            by_name.insert(
+                short_name(&name).to_string(),
+                Signature {
+                    params,
+                    ret_type: ret,
+                },
+            );
+        }
+
+        Ok(ModuleSignatures {
+            by_name,
+            memory_offset: vmctx::memory_definition_offset(wasm)?,
+            capture_limit: DEFAULT_CAPTURE_LIMIT,
+            capture_limits: HashMap::new(),
+            sampling: HashMap::new(),
+            rate_limits: HashMap::new(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Signature> {
+        self.by_name.get(name)
+    }
+
+    /// Replaces the core signatures with the ones that are read from the DWARF in `wasm`, which
+    /// annotates all the functions without any manual work. A signature is only replaced if it
+    /// lowers to the same core signature, so that a wrongly inferred signature is never used.
+    ///
+    /// Returns the number of replaced signatures, which is zero if `wasm` has no debug info.
+    pub fn apply_dwarf(&mut self, wasm: &[u8]) -> anyhow::Result<usize> {
+        let mut applied = 0;
+
+        for (name, signature) in dwarf::read_signatures(wasm)? {
+            let Some(core) = self.by_name.get_mut(&name) else {
+                continue;
+            };
+
+            if !signature.lowers_to(core) {
+                debug!(
+                    "skipping the DWARF signature of {name} since it does not match the core signature"
+                );
+                continue;
+            }
+
+            *core = signature;
+            applied += 1;
+        }
+
+        Ok(applied)
+    }
+
+    /// Replaces the signature of the function `name`, which needs to lower to the same core
+    /// signature, e.g. `(i32, i32) -> i32` can be replaced with `(&str) -> u32`.
+    pub fn set_signature(&mut self, name: &str, signature: Signature) -> anyhow::Result<()> {
+        let core = self
+            .by_name
+            .get_mut(name)
+            .ok_or(anyhow!("unknown function {name}"))?;
+
+        if !signature.lowers_to(core) {
+            bail!("the signature {signature:?} does not match the core signature of {name}");
+        }
+
+        *core = signature;
+
+        Ok(())
+    }
+
+    /// Marks the `(pointer, length)` pair that starts at the param `param_index` of the function
+    /// `name` as [`ParamType::Bytes`]. Note that this merges two params into one, so the indices
+    /// of the following params are shifted by one.
+    pub fn annotate_bytes(&mut self, name: &str, param_index: usize) -> anyhow::Result<()> {
+        let signature = self
+            .by_name
+            .get_mut(name)
+            .ok_or(anyhow!("unknown function {name}"))?;
+
+        if !matches!(
+            signature.params.get(param_index..param_index + 2),
+            Some([ParamType::I32, ParamType::I32])
+        ) {
+            bail!(
+                "params {param_index} and {} of {name} are not a (pointer, length) pair",
+                param_index + 1
+            );
+        }
+
+        signature
+            .params
+            .splice(param_index..param_index + 2, [ParamType::Bytes]);
+
+        Ok(())
+    }
+
+    /// Overrides the type of the param `param_index` of the function `name`, e.g. to mark an `i32`
+    /// as [`ParamType::U32`].
+    pub fn annotate(
+        &mut self,
+        name: &str,
+        param_index: usize,
+        ty: ParamType,
+    ) -> anyhow::Result<()> {
+        let signature = self
+            .by_name
+            .get_mut(name)
+            .ok_or(anyhow!("unknown function {name}"))?;
+
+        let param = signature
+            .params
+            .get_mut(param_index)
+            .ok_or(anyhow!("{name} has no param {param_index}"))?;
+        *param = ty;
+
+        Ok(())
+    }
+
+    /// Sets the maximum number of bytes that are captured from every [`ParamType::Bytes`] param,
+    /// unless it's set for the specific param with [`ModuleSignatures::set_param_capture_limit`].
+    pub fn set_capture_limit(&mut self, limit: u16) {
+        self.capture_limit = limit;
+    }
+
+    /// Sets the maximum number of bytes that are captured from the param `param_index` of the
+    /// function `name`, which needs to be a [`ParamType::Bytes`].
+    pub fn set_param_capture_limit(
+        &mut self,
+        name: &str,
+        param_index: usize,
+        limit: u16,
+    ) -> anyhow::Result<()> {
+        let signature = self
+            .by_name
+            .get(name)
+            .ok_or(anyhow!("unknown function {name}"))?;
+
+        if !matches!(signature.params.get(param_index), Some(ParamType::Bytes)) {
+            bail!("param {param_index} of {name} is not a byte array");
+        }
+
+        self.capture_limits
+            .insert((name.to_string(), param_index), limit);
+
+        Ok(())
+    }
+
+    /// Traces only every `n`th call of the function `name` on every CPU, which is meant for the
+    /// hot functions that would flood the ring buffer otherwise.
+    pub fn set_sampling(&mut self, name: &str, n: u32) -> anyhow::Result<()> {
+        if !self.by_name.contains_key(name) {
+            bail!("unknown function {name}");
+        }
+        if n == 0 {
+            bail!("the sampling of {name} must be positive");
+        }
+
+        self.sampling.insert(name.to_string(), n);
+
+        Ok(())
+    }
+
+    /// Traces at most `per_sec` calls of the function `name` per second on every CPU, with
+    /// bursts of up to `burst` calls. The rate limit applies to the calls that are sampled by
+    /// [`ModuleSignatures::set_sampling`].
+    pub fn set_rate_limit(&mut self, name: &str, per_sec: u32, burst: u32) -> anyhow::Result<()> {
+        if !self.by_name.contains_key(name) {
+            bail!("unknown function {name}");
+        }
+        if per_sec == 0 || burst == 0 {
+            bail!("the rate limit of {name} must be positive");
+        }
+
+        self.rate_limits.insert(name.to_string(), (per_sec, burst));
+
+        Ok(())
+    }
+
+    /// Returns the metadata of the functions that are present in the `mapping`, which can be
+    /// directly passed to the `EbpfRunner`.
+    pub fn function_abi(&self, mapping: &FunctionMapping) -> HashMap<String, FunctionMetadata> {
+        mapping
+            .into_iter()
+            .filter_map(|(_, perf_meta)| {
+                let signature = self.by_name.get(&perf_meta.name)?;
+                // the float params are passed in the xmm registers, which the probe cannot read
+                if signature
+                    .params
+                    .iter()
+                    .any(|ty| matches!(ty, ParamType::F32 | ParamType::F64))
+                {
+                    debug!("skipping {} since it has float params", perf_meta.name);
+                    return None;
+                }
+                let meta = signature.metadata().map(|meta| {
+                    signature
+                        .params
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, ty)| matches!(ty, ParamType::Bytes))
+                        .fold(meta, |meta, (i, _)| {
+                            let limit = self
+                                .capture_limits
+                                .get(&(perf_meta.name.clone(), i))
+                                .copied()
+                                .unwrap_or(self.capture_limit);
+                            meta.with_capture_limit(i, limit)
+                        })
+                        .with_memory_offset(self.memory_offset)
+                });
+                let meta = meta.map(|meta| {
+                    let meta = match self.sampling.get(&perf_meta.name) {
+                        Some(n) => meta.with_sampling(*n),
+                        None => meta,
+                    };
+                    match self.rate_limits.get(&perf_meta.name) {
+                        Some((per_sec, burst)) => meta.with_rate_limit(*per_sec, *burst),
+                        None => meta,
+                    }
+                });
+                if meta.is_none() {
+                    debug!("skipping {} since it has too many params", perf_meta.name);
+                }
+                meta.map(|meta| (perf_meta.name.clone(), meta))
+            })
+            .collect()
+    }
+}
+
+fn param_type(ty: ValType) -> Option<ParamType> {
+    match ty {
+        ValType::I32 => Some(ParamType::I32),
+        ValType::I64 => Some(ParamType::I64),
+        ValType::F32 => Some(ParamType::F32),
+        ValType::F64 => Some(ParamType::F64),
+        _ => None,
+    }
+}
+
+fn core_type(ty: ParamType) -> ParamType {
+    match ty {
+        ParamType::Unspecified => ParamType::Unspecified,
+        ParamType::I64 | ParamType::U64 => ParamType::I64,
+        ParamType::F32 => ParamType::F32,
+        ParamType::F64 => ParamType::F64,
+        _ => ParamType::I32,
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::{Arc, Mutex};
+
+use crate::event::{CompletedCall, TraceEvent};
+
+/// Receives the decoded events from [`crate::ebpf_runner::EbpfRunner::read_events`].
+pub trait EventSink: Send {
+    /// An event of a function whose exits are not traced, so it cannot be paired
+    fn event(&mut self, event: &TraceEvent) -> anyhow::Result<()>;
+
+    /// A call whose entry and exit are both seen
+    fn call(&mut self, call: &CompletedCall) -> anyhow::Result<()>;
+
+    /// Completes the output, no more events are received after this
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink that is shared between the task that reads the events and the one that finishes it on
+/// shutdown.
+pub type SharedSink = Arc<Mutex<dyn EventSink>>;
+
+/// Prints every event to the standard output.
+#[derive(Debug, Default)]
+pub struct PrintSink;
+
+impl PrintSink {
+    pub fn shared() -> SharedSink {
+        Arc::new(Mutex::new(PrintSink))
+    }
+}
+
+impl EventSink for PrintSink {
+    fn event(&mut self, event: &TraceEvent) -> anyhow::Result<()> {
+        println!("{event}");
+        Ok(())
+    }
+
+    fn call(&mut self, call: &CompletedCall) -> anyhow::Result<()> {
+        println!("{call}");
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use object::{Object, ObjectSegment};
+
+use crate::{
+    perf_util::FunctionMapping,
+    proc_maps::{self, FileMapping},
+};
+
+/// Resolves the addresses in the call stacks of a process to the wasm functions, or to the
+/// symbols of the host binary and the shared libraries for the frames outside of the JIT code.
+pub struct Symbolizer {
+    pid: u32,
+    /// The JIT-compiled wasm functions, sorted by address
+    functions: Vec<WasmFunction>,
+    /// Executable file mappings of the process, which are reloaded when an address is not in any
+    /// of them
+    mappings: Vec<FileMapping>,
+    /// Symbols of the mapped files, `None` if the file cannot be read
+    objects: HashMap<PathBuf, Option<HostObject>>,
+}
+
+struct WasmFunction {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+struct HostObject {
+    /// `(file offset, file size, virtual address)` of the segments
+    segments: Vec<(u64, u64, u64)>,
+    /// Demangled symbols sorted by their virtual addresses
+    symbols: Vec<(u64, String)>,
+}
+
+/// A symbolized frame of a call stack.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    /// A JIT-compiled wasm function
+    Wasm {
+        name: String,
+        offset: u64,
+    },
+    /// A function of the host binary or a shared library. `offset` is relative to the symbol if
+    /// it's found, and to the start of the object otherwise.
+    Host {
+        symbol: Option<String>,
+        object: PathBuf,
+        offset: u64,
+    },
+    Unknown(u64),
+}
+
+impl Symbolizer {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            functions: Vec::new(),
+            mappings: Vec::new(),
+            objects: HashMap::new(),
+        }
+    }
+
+    /// Replaces the wasm functions with the ones in `mapping`, which is called whenever the
+    /// mapping is reloaded.
+    pub fn set_functions(&mut self, mapping: &FunctionMapping) {
+        self.functions = mapping
+            .into_iter()
+            .map(|(addr, meta)| WasmFunction {
+                addr: *addr,
+                size: meta.size,
+                name: meta.name.clone(),
+            })
+            .collect();
+        self.functions.sort_by_key(|function| function.addr);
+    }
+
+    /// Symbolizes a call stack whose first frame is the traced function or the sampled
+    /// instruction pointer, and the rest are return addresses.
+    pub fn symbolize_stack(&mut self, stack: &[u64]) -> Vec<Frame> {
+        let mut reloaded = false;
+        stack
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                // a return address points after the `call`, which can be the first instruction
+                // of the next function
+                let lookup_addr = if i == 0 {
+                    *addr
+                } else {
+                    addr.saturating_sub(1)
+                };
+                match self.symbolize(lookup_addr, &mut reloaded) {
+                    Frame::Unknown(_) => Frame::Unknown(*addr),
+                    frame => frame,
+                }
+            })
+            .collect()
+    }
+
+    fn symbolize(&mut self, addr: u64, reloaded: &mut bool) -> Frame {
+        let i = self
+            .functions
+            .partition_point(|function| function.addr <= addr);
+        if let Some(function) = i.checked_sub(1).map(|i| &self.functions[i])
+            && addr < function.addr + function.size
+        {
+            return Frame::Wasm {
+                name: function.name.clone(),
+                offset: addr - function.addr,
+            };
+        }
+
+        let mut mapping = self.find_mapping(addr);
+        // the libraries can be loaded after the mappings are read, which are read at most once
+        // per stack to not read them on every frame that is not in a file
+        if mapping.is_none() && !*reloaded {
+            *reloaded = true;
+            self.mappings = proc_maps::executable_file_mappings(self.pid).unwrap_or_default();
+            mapping = self.find_mapping(addr);
+        }
+        let Some(mapping) = mapping else {
+            return Frame::Unknown(addr);
+        };
+
+        let file_offset = addr - mapping.range.start + mapping.offset;
+        let object = self
+            .objects
+            .entry(mapping.path.clone())
+            .or_insert_with(|| HostObject::read(&mapping.path));
+
+        let Some(object) = object else {
+            return Frame::Host {
+                symbol: None,
+                object: mapping.path,
+                offset: file_offset,
+            };
+        };
+
+        let Some(vaddr) = object.vaddr(file_offset) else {
+            return Frame::Host {
+                symbol: None,
+                object: mapping.path,
+                offset: file_offset,
+            };
+        };
+
+        match object.symbol(vaddr) {
+            Some((symbol_addr, symbol)) => Frame::Host {
+                symbol: Some(symbol.into()),
+                object: mapping.path,
+                offset: vaddr - symbol_addr,
+            },
+            None => Frame::Host {
+                symbol: None,
+                object: mapping.path,
+                offset: vaddr,
+            },
+        }
+    }
+
+    fn find_mapping(&self, addr: u64) -> Option<FileMapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.range.contains(&addr))
+            .cloned()
+    }
+}
+
+impl HostObject {
+    fn read(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+
+        let symbols = file
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|symbol| {
+                (
+                    symbol.address(),
+                    format!("{:#}", rustc_demangle::demangle(symbol.name())),
+                )
+            })
+            .collect();
+
+        Some(HostObject { segments, symbols })
+    }
+
+    fn vaddr(&self, file_offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(offset, size, _)| (*offset..offset + size).contains(&file_offset))
+            .map(|(offset, _, address)| file_offset - offset + address)
+    }
+
+    fn symbol(&self, vaddr: u64) -> Option<(u64, &str)> {
+        let i = self.symbols.partition_point(|(addr, _)| *addr <= vaddr);
+        let (addr, name) = &self.symbols[i.checked_sub(1)?];
+        Some((*addr, name))
+    }
+}
+
+impl Frame {
+    /// The name of the function without the offset, which is how the frame is shown in the
+    /// folded stacks
+    pub fn function_name(&self) -> String {
+        match self {
+            Frame::Wasm { name, .. } => name.clone(),
+            Frame::Host {
+                symbol: Some(symbol),
+                ..
+            } => symbol.clone(),
+            Frame::Host {
+                symbol: None,
+                object,
+                ..
+            } => format!(
+                "[{}]",
+                object
+                    .file_name()
+                    .unwrap_or(object.as_os_str())
+                    .to_string_lossy()
+            ),
+            Frame::Unknown(_) => "[unknown]".into(),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Wasm { name, offset } => write!(f, "{name}+{offset:#x}"),
+            Frame::Host {
+                symbol,
+                object,
+                offset,
+            } => {
+                let object = object.file_name().unwrap_or(object.as_os_str());
+                match symbol {
+                    Some(symbol) => {
+                        write!(f, "{symbol}+{offset:#x} [{}]", object.to_string_lossy())
+                    }
+                    None => write!(f, "{}+{offset:#x}", object.to_string_lossy()),
+                }
+            }
+            Frame::Unknown(addr) => write!(f, "{addr:#x}"),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+use wasm_tracer_abi::{CallCounters, FunctionMetadata};
+
+use crate::function_map::{FunctionMapReader, FunctionReport, MapSummary};
+
+/// The calls of a sampled or rate limited function that are counted in the kernel, summed over
+/// every CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CallStats {
+    /// The calls that pass the filters of the function
+    pub calls: u64,
+    /// The calls that are skipped by the sampling
+    pub sampled_out: u64,
+    /// The calls that are skipped by the rate limit
+    pub rate_limited: u64,
+}
+
+impl CallStats {
+    pub fn skipped(&self) -> u64 {
+        self.sampled_out + self.rate_limited
+    }
+
+    pub fn traced(&self) -> u64 {
+        self.calls.saturating_sub(self.skipped())
+    }
+
+    /// The factor that the traced calls are scaled with to estimate all the calls, `None` if no
+    /// call is traced
+    pub fn scale(&self) -> Option<f64> {
+        (self.traced() > 0).then(|| self.calls as f64 / self.traced() as f64)
+    }
+}
+
+impl MapSummary for CallStats {
+    type Value = CallCounters;
+
+    fn from_value(counters: &CallCounters, _meta: &FunctionMetadata) -> Self {
+        CallStats {
+            calls: counters.calls,
+            sampled_out: counters.sampled_out,
+            rate_limited: counters.rate_limited,
+        }
+    }
+
+    fn merge(&mut self, other: &CallStats) {
+        self.calls += other.calls;
+        self.sampled_out += other.sampled_out;
+        self.rate_limited += other.rate_limited;
+    }
+
+    fn includes(meta: &FunctionMetadata) -> bool {
+        meta.is_throttled()
+    }
+}
+
+/// The [`CallStats`] of every throttled function, keyed by the function name.
+pub type CallStatsReport = FunctionReport<CallStats>;
+
+/// Reads the counters of the throttled functions from the `CallCounters` map.
+pub type CallStatsReader = FunctionMapReader<CallStats>;
+
+impl fmt::Display for CallStatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Skipped calls:")?;
+        for (name, stats) in &self.functions {
+            write!(
+                f,
+                "\n  {name}: traced {} of {} calls, {} sampled out, {} rate limited",
+                stats.traced(),
+                stats.calls,
+                stats.sampled_out,
+                stats.rate_limited
+            )?;
+            if let Some(scale) = stats.scale() {
+                write!(f, ", scale by {scale:.2}")?;
+            }
+        }
+        Ok(())
+    }
+}
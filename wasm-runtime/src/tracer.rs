@@ -0,0 +1,527 @@
+use std::{collections::HashMap, fmt, fs, path::PathBuf};
+
+use anyhow::{anyhow, bail};
+use log::warn;
+use tokio::task::JoinHandle;
+use wasmtime::ProfilingStrategy;
+
+use crate::{
+    aggregate::{AggregateReader, AggregateReport},
+    attach_report::AttachReport,
+    drops::{DropReader, DropReport},
+    ebpf_runner::{EbpfRunner, EventReader, TraceMode},
+    filter::{FilterSpec, compile_filters},
+    latency::{LatencyReader, LatencyReport},
+    perf_util::{FunctionMapping, MAPPING_RELOAD_PERIOD, MappingSource},
+    scheduler::BreakpointScheduler,
+    signature::{ModuleSignatures, Signature},
+    sink::{PrintSink, SharedSink},
+    throttle::{CallStatsReader, CallStatsReport},
+    wasm_runner::{WasmRunner, WasmVM},
+};
+
+/// Traces the functions of a wasm module. The tracer is configured with [`Tracer::builder`].
+/// [`TracerBuilder::start`] loads the module into this process and returns a [`TraceHandle`]
+/// that the functions are called through until the trace is stopped, and
+/// [`TracerBuilder::attach`] traces the module in another process.
+pub struct Tracer;
+
+impl Tracer {
+    pub fn builder() -> TracerBuilder {
+        TracerBuilder::default()
+    }
+}
+
+/// How the module is compiled, which decides where the addresses of its functions are read from
+/// and how the probes are attached.
+#[derive(Debug, Clone, Default)]
+pub enum Compilation {
+    /// JIT-compiled, the addresses are read from the compiled module
+    #[default]
+    Jit,
+    /// JIT-compiled with `ProfilingStrategy::PerfMap`, the addresses are read from
+    /// `/tmp/perf-{pid}.map`
+    PerfMap,
+    /// JIT-compiled with `ProfilingStrategy::JitDump`, the addresses are read from
+    /// `./jit-{pid}.dump`
+    JitDump,
+    /// Precompiled into the `.cwasm` file at the path, which is traced with uprobes instead of
+    /// the hardware breakpoints
+    Precompiled(PathBuf),
+}
+
+/// Configures a [`Tracer`], see the corresponding options of `wasm-trace attach`.
+#[derive(Default)]
+pub struct TracerBuilder {
+    module: Option<PathBuf>,
+    bin_name: Option<String>,
+    /// Functions to trace with their signatures, `None` for the signatures that are read from the
+    /// module
+    functions: Vec<(String, Option<Signature>)>,
+    compilation: Compilation,
+    trace_exits: bool,
+    capture_stacks: bool,
+    capture_limit: Option<u16>,
+    mode: TraceMode,
+    filters: Vec<FilterSpec>,
+    sampling: Vec<(String, u32)>,
+    rate_limits: Vec<(String, u32, u32)>,
+    priorities: HashMap<String, u32>,
+    scheduler: Option<BreakpointScheduler>,
+    sink: Option<SharedSink>,
+    mapping_source: Option<MappingSource>,
+}
+
+impl TracerBuilder {
+    /// The wasm module to load and trace, which is required
+    pub fn module(mut self, path: impl Into<PathBuf>) -> Self {
+        self.module = Some(path.into());
+        self
+    }
+
+    /// The prefix of the functions in the function mapping, defaults to the file name of the
+    /// module
+    pub fn bin_name(mut self, bin_name: impl Into<String>) -> Self {
+        self.bin_name = Some(bin_name.into());
+        self
+    }
+
+    /// Traces the function `name` with `signature`, which needs to lower to the core signature
+    /// of the function. Every function with a known signature is traced if no function is set.
+    pub fn trace(mut self, name: impl Into<String>, signature: Signature) -> Self {
+        self.functions.push((name.into(), Some(signature)));
+        self
+    }
+
+    /// Traces the function `name` with the signature that is read from the module, which is the
+    /// one in the DWARF if the module has debug info.
+    pub fn trace_inferred(mut self, name: impl Into<String>) -> Self {
+        self.functions.push((name.into(), None));
+        self
+    }
+
+    pub fn compilation(mut self, compilation: Compilation) -> Self {
+        self.compilation = compilation;
+        self
+    }
+
+    /// Traces the exits of the functions, which pairs the entries with their return values
+    pub fn exits(mut self, trace_exits: bool) -> Self {
+        self.trace_exits = trace_exits;
+        self
+    }
+
+    /// Captures the call stack of every event
+    pub fn stacks(mut self, capture_stacks: bool) -> Self {
+        self.capture_stacks = capture_stacks;
+        self
+    }
+
+    /// The maximum number of bytes that are captured from a `&str` or `&[u8]` param
+    pub fn capture_limit(mut self, limit: u16) -> Self {
+        self.capture_limit = Some(limit);
+        self
+    }
+
+    pub fn mode(mut self, mode: TraceMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Drops the calls that don't match `filter` in the kernel
+    pub fn filter(mut self, filter: FilterSpec) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Traces only every `n`th call of the function `name`
+    pub fn sample(mut self, name: impl Into<String>, n: u32) -> Self {
+        self.sampling.push((name.into(), n));
+        self
+    }
+
+    /// Traces at most `per_sec` calls of the function `name` per second on every CPU, with
+    /// bursts of up to `burst` calls
+    pub fn rate_limit(mut self, name: impl Into<String>, per_sec: u32, burst: u32) -> Self {
+        self.rate_limits.push((name.into(), per_sec, burst));
+        self
+    }
+
+    /// The functions with higher priorities get the hardware breakpoint slots first
+    pub fn priority(mut self, name: impl Into<String>, priority: u32) -> Self {
+        self.priorities.insert(name.into(), priority);
+        self
+    }
+
+    /// Rotates the hardware breakpoints across the functions with `scheduler` instead of
+    /// attaching them all at once
+    pub fn schedule(mut self, scheduler: BreakpointScheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Where the events are passed to, they are printed by default
+    pub fn sink(mut self, sink: SharedSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Where the functions of the process are read from with [`TracerBuilder::attach`], its
+    /// perf map by default
+    pub fn mapping_source(mut self, source: MappingSource) -> Self {
+        self.mapping_source = Some(source);
+        self
+    }
+
+    /// Loads the module and starts tracing it.
+    pub async fn start<VM: WasmVM>(self) -> anyhow::Result<TraceHandle<VM>>
+    where
+        VM::Data: Default,
+    {
+        self.start_with_data(Default::default()).await
+    }
+
+    /// Same as [`TracerBuilder::start`] but the store of the module is created with `data`.
+    pub async fn start_with_data<VM: WasmVM>(
+        self,
+        data: VM::Data,
+    ) -> anyhow::Result<TraceHandle<VM>> {
+        let (module, bin_name) = self.target()?;
+
+        let wasm_runner = match &self.compilation {
+            Compilation::Jit => WasmRunner::<VM>::load(&module, data)?,
+            Compilation::PerfMap => {
+                WasmRunner::<VM>::load_with_profiler(&module, ProfilingStrategy::PerfMap, data)?
+            }
+            Compilation::JitDump => {
+                WasmRunner::<VM>::load_with_profiler(&module, ProfilingStrategy::JitDump, data)?
+            }
+            Compilation::Precompiled(cwasm_path) => {
+                WasmRunner::<VM>::load_precompiled(&module, cwasm_path, data)?
+            }
+        };
+
+        let pid = std::process::id();
+        let function_mapping = match &self.compilation {
+            Compilation::PerfMap => {
+                FunctionMapping::generate_from_perfmap_file_with_pid(&bin_name, pid)?
+            }
+            Compilation::JitDump => {
+                FunctionMapping::from_jitdump(format!("./jit-{pid}.dump"), &bin_name)?
+            }
+            Compilation::Jit | Compilation::Precompiled(_) => {
+                wasm_runner.function_mapping(&bin_name)
+            }
+        };
+
+        let function_abi = self.function_abi(&wasm_runner.wasm, &function_mapping)?;
+        // the whole module is compiled by now
+        for (name, _) in &self.functions {
+            if !function_abi.contains_key(name) {
+                bail!("cannot trace {name}, it's not compiled or its signature is not supported");
+            }
+        }
+
+        let uprobes = match &self.compilation {
+            Compilation::Precompiled(cwasm_path) => {
+                Some((cwasm_path.clone(), wasm_runner.image_base()))
+            }
+            _ => None,
+        };
+        let trace = self
+            .run(pid, function_abi, function_mapping, None, uprobes)
+            .await?;
+
+        Ok(TraceHandle { wasm_runner, trace })
+    }
+
+    /// Traces the module that the process `pid` runs, which has to be JIT-compiled by wasmtime
+    /// with the perf map or the jitdump profiling strategy. The module is only read for the
+    /// signatures of its functions. The function mapping is reloaded periodically, so the
+    /// functions that are compiled later are traced too.
+    pub async fn attach(self, pid: u32) -> anyhow::Result<AttachHandle> {
+        let (module, bin_name) = self.target()?;
+        if let Compilation::Precompiled(_) = self.compilation {
+            bail!("uprobes can only be attached to a module that is run by this process");
+        }
+
+        let mapping_source = self
+            .mapping_source
+            .clone()
+            .unwrap_or_else(|| MappingSource::perf_map(pid));
+        let function_mapping = mapping_source.read(&bin_name)?;
+
+        let wasm = fs::read(&module)?;
+        let function_abi = self.function_abi(&wasm, &function_mapping)?;
+
+        let trace = self
+            .run(
+                pid,
+                function_abi,
+                function_mapping,
+                Some((mapping_source, bin_name)),
+                None,
+            )
+            .await?;
+
+        Ok(AttachHandle { trace })
+    }
+
+    /// Checks the options and returns the module to trace with the prefix of its functions.
+    fn target(&self) -> anyhow::Result<(PathBuf, String)> {
+        let module = self
+            .module
+            .clone()
+            .ok_or(anyhow!("the module to trace is not set"))?;
+        if self.mode != TraceMode::Events
+            && (!self.sampling.is_empty() || !self.rate_limits.is_empty())
+        {
+            bail!(
+                "the calls cannot be sampled or rate limited in {:?} mode",
+                self.mode
+            );
+        }
+        let bin_name = match &self.bin_name {
+            Some(bin_name) => bin_name.clone(),
+            None => module
+                .file_stem()
+                .ok_or(anyhow!("invalid module path {}", module.display()))?
+                .to_string_lossy()
+                .replace('-', "_"),
+        };
+
+        Ok((module, bin_name))
+    }
+
+    /// Reads the signatures of the functions in `function_mapping` from the module and keeps the
+    /// traced ones.
+    fn function_abi(
+        &self,
+        wasm: &[u8],
+        function_mapping: &FunctionMapping,
+    ) -> anyhow::Result<HashMap<String, wasm_tracer_abi::FunctionMetadata>> {
+        let mut signatures = ModuleSignatures::parse(wasm)?;
+        if signatures.apply_dwarf(wasm)? == 0 {
+            warn!("the module has no debug info, only the core wasm types will be traced");
+        }
+        for (name, signature) in &self.functions {
+            if let Some(signature) = signature {
+                signatures.set_signature(name, signature.clone())?;
+            }
+        }
+        if let Some(capture_limit) = self.capture_limit {
+            signatures.set_capture_limit(capture_limit);
+        }
+        for (name, n) in &self.sampling {
+            signatures.set_sampling(name, *n)?;
+        }
+        for (name, per_sec, burst) in &self.rate_limits {
+            signatures.set_rate_limit(name, *per_sec, *burst)?;
+        }
+
+        Ok(signatures
+            .function_abi(function_mapping)
+            .into_iter()
+            .filter(|(name, _)| {
+                self.functions.is_empty() || self.functions.iter().any(|(f, _)| f == name)
+            })
+            .collect())
+    }
+
+    /// Loads the probes into the process `pid`, attaches them and reads the events until the
+    /// trace is stopped. The function mapping is reloaded from the `watcher` source if it's set,
+    /// and the probes are attached as uprobes to the `.cwasm` file mapped at the image base if
+    /// `uprobes` is set.
+    async fn run(
+        self,
+        pid: u32,
+        function_abi: HashMap<String, wasm_tracer_abi::FunctionMetadata>,
+        function_mapping: FunctionMapping,
+        watcher: Option<(MappingSource, String)>,
+        uprobes: Option<(PathBuf, u64)>,
+    ) -> anyhow::Result<RunningTrace> {
+        let filters = compile_filters(&self.filters, &function_abi)?;
+
+        let mut ebpf_runner = EbpfRunner::load(
+            concat!(env!("OUT_DIR"), "/wasm-tracer-ebpf"),
+            pid,
+            function_abi,
+            function_mapping,
+            self.trace_exits,
+            self.capture_stacks,
+            self.mode,
+        )
+        .await?;
+        ebpf_runner.set_filters(filters)?;
+        ebpf_runner.set_priorities(self.priorities);
+        if let Some((mapping_source, bin_name)) = watcher {
+            ebpf_runner.set_mapping_watcher(MAPPING_RELOAD_PERIOD, move || {
+                mapping_source.read(&bin_name)
+            });
+        }
+
+        let sink = self.sink.unwrap_or_else(PrintSink::shared);
+        let reader = ebpf_runner.read_events(sink.clone()).await?;
+
+        let attach_report = match (&self.scheduler, uprobes) {
+            (Some(_), _) => None,
+            (None, Some((cwasm_path, image_base))) => {
+                ebpf_runner.attach_uprobes(cwasm_path, image_base)?;
+                None
+            }
+            (None, None) => Some(ebpf_runner.attach_multi()?),
+        };
+
+        let call_stats = ebpf_runner.call_stats();
+        let drop_stats = ebpf_runner.drop_stats();
+        let aggregates = ebpf_runner.aggregates();
+        let latencies = ebpf_runner.latencies();
+
+        // the probes stay attached as long as the runner is alive, so it's kept in the task
+        // until the trace is stopped
+        let scheduler = self.scheduler;
+        let task = tokio::task::spawn(async move {
+            match scheduler {
+                Some(scheduler) => ebpf_runner.run_scheduler(scheduler).await,
+                None => ebpf_runner.run_mapping_watcher().await,
+            }
+        });
+
+        Ok(RunningTrace {
+            attach_report,
+            mode: self.mode,
+            sink,
+            call_stats,
+            drop_stats,
+            aggregates,
+            latencies,
+            task,
+            reader,
+        })
+    }
+}
+
+/// A running trace that is started with [`TracerBuilder::start`].
+pub struct TraceHandle<VM: WasmVM> {
+    /// The traced module, whose functions are called through this while the trace runs
+    pub wasm_runner: WasmRunner<VM>,
+    trace: RunningTrace,
+}
+
+impl<VM: WasmVM> TraceHandle<VM> {
+    /// The functions that the hardware breakpoints are attached to at the start.
+    pub fn attach_report(&self) -> Option<&AttachReport> {
+        self.trace.attach_report.as_ref()
+    }
+
+    /// Reads the stats of the trace so far.
+    pub fn stats(&self) -> anyhow::Result<TraceStats> {
+        self.trace.stats()
+    }
+
+    /// Detaches the probes, completes the sink and returns the final stats of the trace.
+    pub async fn stop(self) -> anyhow::Result<TraceStats> {
+        self.trace.stop().await
+    }
+}
+
+/// A running trace of another process that is started with [`TracerBuilder::attach`].
+pub struct AttachHandle {
+    trace: RunningTrace,
+}
+
+impl AttachHandle {
+    /// The functions that the hardware breakpoints are attached to at the start.
+    pub fn attach_report(&self) -> Option<&AttachReport> {
+        self.trace.attach_report.as_ref()
+    }
+
+    /// Reads the stats of the trace so far.
+    pub fn stats(&self) -> anyhow::Result<TraceStats> {
+        self.trace.stats()
+    }
+
+    /// Detaches the probes, completes the sink and returns the final stats of the trace.
+    pub async fn stop(self) -> anyhow::Result<TraceStats> {
+        self.trace.stop().await
+    }
+}
+
+/// The probes of a trace with the readers of their events and stats.
+struct RunningTrace {
+    /// `None` if the breakpoints are scheduled or uprobes are used
+    attach_report: Option<AttachReport>,
+    mode: TraceMode,
+    sink: SharedSink,
+    call_stats: CallStatsReader,
+    drop_stats: DropReader,
+    aggregates: AggregateReader,
+    latencies: LatencyReader,
+    /// Runs the `EbpfRunner`
+    task: JoinHandle<anyhow::Result<()>>,
+    /// Decodes the events and passes them to the `sink`
+    reader: EventReader,
+}
+
+impl RunningTrace {
+    fn stats(&self) -> anyhow::Result<TraceStats> {
+        Ok(TraceStats {
+            skipped: self.call_stats.read()?,
+            dropped: self.drop_stats.read()?,
+            aggregates: match self.mode {
+                TraceMode::Aggregate => Some(self.aggregates.read()?),
+                _ => None,
+            },
+            latencies: match self.mode {
+                TraceMode::Latency => Some(self.latencies.read()?),
+                _ => None,
+            },
+        })
+    }
+
+    async fn stop(mut self) -> anyhow::Result<TraceStats> {
+        // the probes are detached when the runner is dropped with its task
+        self.task.abort();
+        match (&mut self.task).await {
+            Ok(res) => res?,
+            Err(e) if e.is_cancelled() => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let stats = self.stats()?;
+
+        // no event is queued anymore, so the ring buffer is drained before the sink is finished
+        self.reader.stop().await?;
+        self.sink.lock().unwrap().finish()?;
+
+        Ok(stats)
+    }
+}
+
+/// The stats of a trace, which are counted in the kernel.
+#[derive(Debug)]
+pub struct TraceStats {
+    /// The calls that are skipped by the sampling and the rate limits
+    pub skipped: CallStatsReport,
+    /// The calls that are dropped by the filters or cannot be captured
+    pub dropped: DropReport,
+    /// Only set in [`TraceMode::Aggregate`]
+    pub aggregates: Option<AggregateReport>,
+    /// Only set in [`TraceMode::Latency`]
+    pub latencies: Option<LatencyReport>,
+}
+
+impl fmt::Display for TraceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(aggregates) = &self.aggregates {
+            writeln!(f, "{aggregates}")?;
+        }
+        if let Some(latencies) = &self.latencies {
+            writeln!(f, "{latencies}")?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "{}", self.skipped)?;
+        }
+        write!(f, "{}", self.dropped)
+    }
+}
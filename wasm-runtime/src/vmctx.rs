@@ -0,0 +1,63 @@
+use wasmparser::{Parser, Payload, TypeRef};
+use wasmtime_environ::{
+    DefinedMemoryIndex, EntityRef, HostPtr, MemoryIndex, VMOffsets, VMOffsetsFields,
+};
+
+/// Returns the offset of the `*mut VMMemoryDefinition` of the first memory in the `VMContext`
+/// of the instances of `wasm`, or `0` if the module has no memory.
+///
+/// Every function gets the `VMContext` of its instance in `rdi`, and the memory definition that
+/// it points to is updated when the memory grows, so the probes can find the current base and
+/// length of the linear memory of any instance from this offset.
+pub fn memory_definition_offset(wasm: &[u8]) -> anyhow::Result<u32> {
+    let mut num_imported_memories = 0;
+    let mut num_defined_memories = 0;
+    let mut num_owned_memories = 0;
+
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader.into_imports() {
+                    if let TypeRef::Memory(_) = import?.ty {
+                        num_imported_memories += 1;
+                    }
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    num_defined_memories += 1;
+                    if !memory?.shared {
+                        num_owned_memories += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // the memories are at the start of the variable-width part of the `VMContext`, so the other
+    // counts don't change their offsets
+    let offsets = VMOffsets::from(VMOffsetsFields {
+        ptr: HostPtr,
+        num_imported_functions: 0,
+        num_imported_tables: 0,
+        num_imported_memories,
+        num_imported_globals: 0,
+        num_imported_tags: 0,
+        num_defined_tables: 0,
+        num_defined_memories,
+        num_owned_memories,
+        num_defined_globals: 0,
+        num_defined_tags: 0,
+        num_escaped_funcs: 0,
+    });
+
+    if num_imported_memories > 0 {
+        // `VMMemoryImport::from` points to the definition in the exporting instance
+        Ok(offsets.vmctx_vmmemory_import_from(MemoryIndex::new(0)))
+    } else if num_defined_memories > 0 {
+        Ok(offsets.vmctx_vmmemory_pointer(DefinedMemoryIndex::new(0)))
+    } else {
+        Ok(0)
+    }
+}
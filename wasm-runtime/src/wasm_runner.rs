@@ -5,6 +5,8 @@ use wasmtime::{
     Config, Engine, Instance, Linker, Module, ProfilingStrategy, Store, WasmParams, WasmResults,
 };
 
+use crate::perf_util::FunctionMapping;
+
 pub trait WasmVM {
     const ALLOC_FN_NAME: &str;
     const MEMORY_NAME: &str;
@@ -13,6 +15,8 @@ pub trait WasmVM {
 }
 
 pub struct WasmRunner<VM: WasmVM> {
+    /// The raw wasm binary that the `module` is compiled from
+    pub wasm: Vec<u8>,
     pub module: Module,
     pub linker: Linker<VM::Data>,
     pub engine: Engine,
@@ -30,13 +34,60 @@ pub struct WasmSlice {
 
 impl<VM: WasmVM> WasmRunner<VM> {
     pub fn load<P: AsRef<Path>>(path: P, data: VM::Data) -> anyhow::Result<Self> {
-        let mut config = Config::new();
-        config.profiler(ProfilingStrategy::PerfMap);
+        Self::load_with_profiler(path, ProfilingStrategy::None, data)
+    }
 
-        let engine = Engine::new(&config)?;
+    /// Same as [`WasmRunner::load`] but the JIT-compiled functions are also reported with
+    /// `profiler`, which is only needed when the functions are read from the profiler output.
+    /// `ProfilingStrategy::PerfMap` writes `/tmp/perf-{pid}.map` and `ProfilingStrategy::JitDump`
+    /// writes `./jit-{pid}.dump`.
+    pub fn load_with_profiler<P: AsRef<Path>>(
+        path: P,
+        profiler: ProfilingStrategy,
+        data: VM::Data,
+    ) -> anyhow::Result<Self> {
+        let engine = Self::engine(profiler)?;
 
-        let module = Module::new(&engine, fs::read(path)?)?;
+        let wasm = fs::read(path)?;
+
+        let module = Module::new(&engine, &wasm)?;
+
+        Self::instantiate(engine, wasm, module, data)
+    }
 
+    /// Precompiles the module into an ELF `.cwasm` at `cwasm_path` and runs the code directly
+    /// from a mapping of that file, which makes it possible to attach uprobes to the functions.
+    pub fn load_precompiled<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        cwasm_path: Q,
+        data: VM::Data,
+    ) -> anyhow::Result<Self> {
+        let engine = Self::engine(ProfilingStrategy::None)?;
+
+        let wasm = fs::read(path)?;
+
+        fs::write(&cwasm_path, engine.precompile_module(&wasm)?)?;
+
+        // SAFETY: the file is just produced by the same engine and it's not modified while
+        // it's mapped
+        let module = unsafe { Module::deserialize_file(&engine, &cwasm_path)? };
+
+        Self::instantiate(engine, wasm, module, data)
+    }
+
+    fn engine(profiler: ProfilingStrategy) -> anyhow::Result<Engine> {
+        let mut config = Config::new();
+        config.profiler(profiler);
+
+        Ok(Engine::new(&config)?)
+    }
+
+    fn instantiate(
+        engine: Engine,
+        wasm: Vec<u8>,
+        module: Module,
+        data: VM::Data,
+    ) -> anyhow::Result<Self> {
         let linker = Linker::new(&engine);
 
         let mut store = Store::new(&engine, data);
@@ -44,6 +95,7 @@ impl<VM: WasmVM> WasmRunner<VM> {
         let instance = linker.instantiate(&mut store, &module)?;
 
         Ok(WasmRunner {
+            wasm,
             module,
             linker,
             engine,
@@ -82,12 +134,15 @@ impl<VM: WasmVM> WasmRunner<VM> {
         })
     }
 
-    pub fn get_memory_base(&mut self) -> anyhow::Result<u64> {
-        let memory = self
-            .instance
-            .get_memory(&mut self.store, VM::MEMORY_NAME)
-            .ok_or(anyhow!("could not find the memory"))?;
+    /// The address that the compilation image of the module is mapped at. When the module is
+    /// loaded with [`WasmRunner::load_precompiled`], the image is the whole `.cwasm` file, so
+    /// `addr - image_base()` is the file offset of the code at `addr`.
+    pub fn image_base(&self) -> u64 {
+        self.module.image_range().start as u64
+    }
 
-        Ok(memory.data_ptr(&self.store) as u64)
+    /// The addresses of the functions of the module whose names start with `bin_name`.
+    pub fn function_mapping(&self, bin_name: &str) -> FunctionMapping {
+        FunctionMapping::from_module(&self.module, bin_name)
     }
 }
@@ -2,11 +2,36 @@
 
 pub const MAX_PARAM_COUNT: usize = 5;
 
+/// The number of bytes that are captured from a [`ParamType::Bytes`] param unless it's
+/// configured otherwise
+pub const DEFAULT_CAPTURE_LIMIT: u16 = 32;
+
+/// The upper bound of the capture limits, which bounds the loop that copies the bytes in the
+/// eBPF program
+pub const MAX_CAPTURE_LIMIT: u16 = 128;
+
 #[cfg_attr(feature = "userspace", derive(Debug, Copy, Clone))]
 #[repr(C)]
 pub struct FunctionMetadata {
     pub param_types: ParamTypes,
     pub param_count: usize,
+    /// The type of the value that is returned in the return register, only used when the exits
+    /// of the function are traced
+    pub ret_type: ParamType,
+    /// Offset of the `*mut VMMemoryDefinition` of the first memory in the `VMContext` of the
+    /// function's module, `0` if the module has no memory
+    pub memory_offset: u32,
+    /// The maximum number of bytes that are captured from every [`ParamType::Bytes`] param.
+    /// Longer values are truncated.
+    pub capture_limits: [u16; MAX_PARAM_COUNT],
+    /// Only every `sample_every`th call is traced on every CPU, every call is traced if it's `0`
+    /// or `1`
+    pub sample_every: u32,
+    /// The maximum number of calls per second that are traced on every CPU, unlimited if it's
+    /// `0`
+    pub rate_limit: u32,
+    /// The number of calls that can be traced in a burst before the rate limit applies
+    pub rate_burst: u32,
 }
 
 #[cfg(feature = "userspace")]
@@ -26,6 +51,12 @@ impl FunctionMetadata {
         Ok(FunctionMetadata {
             param_types: param_types_array,
             param_count: param_types.len(),
+            ret_type: ParamType::Unspecified,
+            memory_offset: 0,
+            capture_limits: [DEFAULT_CAPTURE_LIMIT; MAX_PARAM_COUNT],
+            sample_every: 0,
+            rate_limit: 0,
+            rate_burst: 0,
         })
     }
 
@@ -38,11 +69,185 @@ impl FunctionMetadata {
 
         ret
     }
+
+    pub const fn with_ret(mut self, ret_type: ParamType) -> Self {
+        self.ret_type = ret_type;
+        self
+    }
+
+    pub const fn with_memory_offset(mut self, memory_offset: u32) -> Self {
+        self.memory_offset = memory_offset;
+        self
+    }
+
+    /// Sets the capture limit of the param `param_index`, which is clamped to
+    /// [`MAX_CAPTURE_LIMIT`].
+    pub const fn with_capture_limit(mut self, param_index: usize, limit: u16) -> Self {
+        assert!(param_index < MAX_PARAM_COUNT);
+
+        self.capture_limits[param_index] = if limit > MAX_CAPTURE_LIMIT {
+            MAX_CAPTURE_LIMIT
+        } else {
+            limit
+        };
+        self
+    }
+
+    /// Traces only every `n`th call of the function.
+    pub const fn with_sampling(mut self, n: u32) -> Self {
+        self.sample_every = n;
+        self
+    }
+
+    /// Traces at most `per_sec` calls of the function per second on every CPU, with bursts of up
+    /// to `burst` calls.
+    pub const fn with_rate_limit(mut self, per_sec: u32, burst: u32) -> Self {
+        self.rate_limit = per_sec;
+        self.rate_burst = burst;
+        self
+    }
+}
+
+impl FunctionMetadata {
+    /// Whether some calls of the function are skipped by the sampling or the rate limit
+    pub const fn is_throttled(&self) -> bool {
+        self.sample_every > 1 || self.rate_limit > 0
+    }
 }
 
 #[cfg(feature = "userspace")]
 unsafe impl aya::Pod for FunctionMetadata {}
 
+/// The per-CPU state of the sampling and the rate limit of a function, which is keyed by the
+/// function address in the `CallCounters` map. Only the throttled functions have one.
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct CallCounters {
+    /// The calls that pass the filters of the function
+    pub calls: u64,
+    /// The calls that are skipped by the sampling
+    pub sampled_out: u64,
+    /// The calls that are skipped by the rate limit
+    pub rate_limited: u64,
+    /// The tokens of the rate limit, in billionths of a call
+    pub tokens: u64,
+    /// `bpf_ktime_get_ns` at the last refill of `tokens`
+    pub last_refill: u64,
+}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for CallCounters {}
+
+/// The number of buckets of the [`FunctionAggregate::bytes_lens`] histograms. Bucket `0` counts
+/// the empty values and bucket `i` the lengths in `[2^(i-1), 2^i)`.
+pub const LEN_BUCKETS: usize = 33;
+
+/// The statistics of a numeric param, which are compared and summed as `i64`s if the param is
+/// signed
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct ParamAggregate {
+    pub min: u64,
+    pub max: u64,
+    /// The wrapping sum of the values
+    pub sum: u64,
+}
+
+/// The per-CPU aggregates of the calls of a function when the calls are aggregated instead of
+/// being sent as events, which are keyed by the function address in the `CallAggregates` map.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct FunctionAggregate {
+    /// The calls that match the filters of the function
+    pub calls: u64,
+    /// The calls with a `Bytes` param that points outside of the linear memory, whose params are
+    /// not aggregated
+    pub invalid_guest_pointers: u64,
+    /// The statistics of the numeric params, unused for the other params
+    pub params: [ParamAggregate; MAX_PARAM_COUNT],
+    /// The log2 histograms of the lengths of the `Bytes` params, unused for the other params
+    pub bytes_lens: [[u64; LEN_BUCKETS]; MAX_PARAM_COUNT],
+}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for FunctionAggregate {}
+
+/// The number of buckets of a [`LatencyHistogram`]. Bucket `0` counts the calls that take no
+/// time and bucket `i` the latencies in `[2^(i-1), 2^i)` nanoseconds, the last one is unbounded.
+pub const LATENCY_BUCKETS: usize = 64;
+
+/// The per-CPU latencies of the calls of a function, which are keyed by the function address in
+/// the `CallLatencies` map. A call is counted on the CPU that it returns on.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct LatencyHistogram {
+    pub calls: u64,
+    pub total_ns: u64,
+    pub max_ns: u64,
+    /// The log2 histogram of the latencies in nanoseconds
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for LatencyHistogram {}
+
+/// Why a call of a traced function is not sent to the userspace, which is counted per function in
+/// the `DropCounts` map.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug, PartialEq, Eq, Hash))]
+#[repr(u8)]
+pub enum DropReason {
+    /// The `FunctionCalls` ring buffer has no room for the record, the userspace is too slow
+    RingBufferFull = 0,
+    /// Reading a param from the stack or the linear memory of the process failed
+    ReadFailed,
+    /// A param or the return value has a type that cannot be captured, e.g. a float
+    UnsupportedType,
+    /// The captured values do not fit in the record
+    RecordTooLong,
+    /// A `Bytes` param is read from a module without a linear memory
+    NoLinearMemory,
+    /// The [`FunctionMetadata`] of the function is invalid
+    InvalidMetadata,
+    /// A map of the eBPF program has no room for the function
+    MapFull,
+    /// The call does not match the filters of the function. This is not a failure, but it's
+    /// counted so that every call is accounted for.
+    Filtered,
+}
+
+/// The number of [`DropReason`]s
+pub const DROP_REASON_COUNT: usize = 8;
+
+impl DropReason {
+    pub const ALL: [DropReason; DROP_REASON_COUNT] = [
+        DropReason::RingBufferFull,
+        DropReason::ReadFailed,
+        DropReason::UnsupportedType,
+        DropReason::RecordTooLong,
+        DropReason::NoLinearMemory,
+        DropReason::InvalidMetadata,
+        DropReason::MapFull,
+        DropReason::Filtered,
+    ];
+}
+
+/// The per-CPU drops of the calls of a function, indexed by the [`DropReason`], which are keyed
+/// by the function address in the `DropCounts` map.
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct DropCounters {
+    pub counts: [u64; DROP_REASON_COUNT],
+}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for DropCounters {}
+
 #[cfg_attr(feature = "userspace", derive(Debug, Copy, Clone))]
 #[repr(u8)]
 pub enum ParamType {
@@ -56,12 +261,174 @@ pub enum ParamType {
     F32,
     F64,
 
-    /// 1 word for length and 1 word for the pointer to the byte array
+    /// 1 word for length and 1 word for the pointer to the byte array. It's captured as the
+    /// original length (`u32`), the captured length (`u32`) and the captured bytes.
     Bytes,
 }
 
 pub type ParamTypes = [ParamType; MAX_PARAM_COUNT];
 
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug, PartialEq, Eq))]
+#[repr(u8)]
+pub enum EventKind {
+    /// The function is entered, the params are captured
+    Entry = 0,
+    /// The function returned, the return value is captured
+    Exit,
+    /// The function is entered, but a `Bytes` param points outside of the linear memory, so the
+    /// params are not captured. The data is the param index (`u32`), the pointer, the length and
+    /// the size of the linear memory (`u64`s).
+    InvalidGuestPointer,
+}
+
+/// The version of the [`EventHeader`] layout, which is bumped on every incompatible change of the
+/// records
+pub const EVENT_VERSION: u8 = 3;
+
+pub const EVENT_HEADER_LEN: usize = core::mem::size_of::<EventHeader>();
+
+/// The maximum length of a record including its header
+pub const MAX_EVENT_LEN: usize = 1024;
+
+/// The maximum number of frames that are captured in the call stack of an event
+pub const MAX_STACK_DEPTH: usize = 32;
+
+/// The header of every record in the `FunctionCalls` ring buffer, which is followed by
+/// `len - EVENT_HEADER_LEN` bytes of payload. The payload is the captured params or the return
+/// value, whose layout is driven by the [`FunctionMetadata`] of the function at `addr`, followed
+/// by `stack_depth` return addresses (`u64`s) of the call stack.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct EventHeader {
+    /// [`EVENT_VERSION`]
+    pub version: u8,
+    /// Raw [`EventKind`]
+    pub kind: u8,
+    /// The number of values in the payload
+    pub param_count: u8,
+    /// The number of frames at the end of the payload, the innermost first. `0` unless the
+    /// stacks are captured.
+    pub stack_depth: u8,
+    /// The length of the whole record including the header
+    pub len: u32,
+    /// The address of the traced function
+    pub addr: u64,
+    /// The stack pointer at the time of the event. It is the same at the entry and the `ret`
+    /// of a call, so it's used to pair them.
+    pub stack_pointer: u64,
+    /// The scheduler window that the event is captured in, `0` if the breakpoints are not
+    /// scheduled
+    pub window: u64,
+    /// `bpf_ktime_get_ns` at the time of the event, which is `CLOCK_MONOTONIC` in nanoseconds
+    pub timestamp: u64,
+    /// The process (thread group) that the event is captured in
+    pub tgid: u32,
+    /// The thread that the event is captured in
+    pub tid: u32,
+    /// The CPU that the event is captured on
+    pub cpu: u32,
+    pub _padding: u32,
+}
+
+/// The maximum number of filters of a function
+pub const MAX_FILTERS: usize = 4;
+
+/// The maximum length of the pattern of a [`FilterOp::BytesEq`] or a [`FilterOp::StartsWith`]
+/// filter
+pub const MAX_FILTER_PATTERN_LEN: usize = 16;
+
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug, PartialEq, Eq))]
+#[repr(u8)]
+pub enum FilterOp {
+    /// An unused filter slot
+    None = 0,
+    /// The numeric ops compare the param with `value`, as `i64`s if the param is signed
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// The captured bytes of a `Bytes` param are equal to the pattern
+    BytesEq,
+    /// The captured bytes of a `Bytes` param start with the pattern
+    StartsWith,
+}
+
+impl FilterOp {
+    pub const fn from_u8(op: u8) -> Option<Self> {
+        match op {
+            0 => Some(FilterOp::None),
+            1 => Some(FilterOp::Eq),
+            2 => Some(FilterOp::Ne),
+            3 => Some(FilterOp::Lt),
+            4 => Some(FilterOp::Le),
+            5 => Some(FilterOp::Gt),
+            6 => Some(FilterOp::Ge),
+            7 => Some(FilterOp::BytesEq),
+            8 => Some(FilterOp::StartsWith),
+            _ => None,
+        }
+    }
+}
+
+/// A predicate on a param of a function, which is evaluated in the kernel after the params are
+/// captured.
+#[cfg_attr(feature = "userspace", derive(Debug, Copy, Clone))]
+#[repr(C)]
+pub struct Filter {
+    /// Raw [`FilterOp`]
+    pub op: u8,
+    pub param_index: u8,
+    /// The length of `pattern` that is compared
+    pub pattern_len: u8,
+    pub _padding: [u8; 5],
+    /// The value that the numeric params are compared with, sign-extended for the signed params
+    pub value: u64,
+    pub pattern: [u8; MAX_FILTER_PATTERN_LEN],
+}
+
+/// The filters of a function, which are keyed by the function address in the
+/// `FunctionFilters` map. The calls that don't match all of them are dropped in the kernel.
+#[cfg_attr(feature = "userspace", derive(Debug, Copy, Clone))]
+#[repr(C)]
+pub struct FunctionFilters {
+    pub filters: [Filter; MAX_FILTERS],
+}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for FunctionFilters {}
+
+pub const SAMPLE_HEADER_LEN: usize = core::mem::size_of::<SampleHeader>();
+
+/// The header of every record in the `Samples` ring buffer, which is followed by `stack_depth`
+/// frames (`u64`s) of the user stack, the sampled instruction pointer first.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "userspace", derive(Debug))]
+#[repr(C)]
+pub struct SampleHeader {
+    /// `bpf_ktime_get_ns` at the time of the sample
+    pub timestamp: u64,
+    pub tgid: u32,
+    pub tid: u32,
+    pub cpu: u32,
+    pub stack_depth: u32,
+}
+
+impl EventKind {
+    pub const fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(EventKind::Entry),
+            1 => Some(EventKind::Exit),
+            2 => Some(EventKind::InvalidGuestPointer),
+            _ => None,
+        }
+    }
+}
+
 // let first_str_ptr = read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.rcx) });
 // let first_str_len = read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.r8) });
 // let second_str_ptr = read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.r9) });
@@ -2,17 +2,24 @@
 #![no_main]
 
 use aya_ebpf::{
-    bindings::bpf_perf_event_data,
-    cty::c_ulong,
-    helpers::bpf_probe_read_user,
-    macros::{map, perf_event},
-    maps::{HashMap, RingBuf, ring_buf::RingBufBytes},
-    programs::PerfEventContext,
+    bindings::{BPF_F_USER_STACK, BPF_NOEXIST, bpf_perf_event_data, pt_regs},
+    cty::{c_ulong, c_void},
+    helpers::{
+        bpf_get_current_pid_tgid, bpf_get_smp_processor_id, bpf_get_stack, bpf_ktime_get_ns,
+        bpf_probe_read_user,
+    },
+    macros::{map, perf_event, uprobe},
+    maps::{Array, HashMap, LruHashMap, PerCpuArray, PerCpuHashMap, RingBuf},
+    programs::{PerfEventContext, ProbeContext},
 };
 use aya_log_ebpf::info;
-use wasm_tracer_abi::FunctionMetadata;
-
-pub const MAX_DATA_LEN: usize = 256;
+use wasm_tracer_abi::{
+    CallCounters, DropCounters, DropReason, EVENT_HEADER_LEN, EVENT_VERSION, EventHeader,
+    EventKind, Filter, FilterOp, FunctionAggregate, FunctionFilters, FunctionMetadata,
+    LATENCY_BUCKETS, LEN_BUCKETS, LatencyHistogram, MAX_CAPTURE_LIMIT, MAX_EVENT_LEN,
+    MAX_FILTER_PATTERN_LEN, MAX_PARAM_COUNT, MAX_STACK_DEPTH, ParamType, SAMPLE_HEADER_LEN,
+    SampleHeader,
+};
 
 #[map(name = "FunctionCalls")]
 static FUNCTION_CALLS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
@@ -20,72 +27,689 @@ static FUNCTION_CALLS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
 #[map(name = "FunctionTypes")]
 static FUNC_TYPES: HashMap<u64, FunctionMetadata> = HashMap::with_max_entries(1024, 0);
 
+/// The filters of the functions, keyed by the function address. The functions without filters
+/// are not in this map.
+#[map(name = "FunctionFilters")]
+static FUNC_FILTERS: HashMap<u64, FunctionFilters> = HashMap::with_max_entries(1024, 0);
+
+/// The sampling and rate limit state of the throttled functions on every CPU, keyed by the
+/// function address
+#[map(name = "CallCounters")]
+static CALL_COUNTERS: PerCpuHashMap<u64, CallCounters> = PerCpuHashMap::with_max_entries(1024, 0);
+
+/// The aggregates of the calls of every function on every CPU when the calls are aggregated,
+/// keyed by the function address
+#[map(name = "CallAggregates")]
+static CALL_AGGREGATES: PerCpuHashMap<u64, FunctionAggregate> =
+    PerCpuHashMap::with_max_entries(1024, 0);
+
+/// A zeroed aggregate that the aggregates of the functions are created from, since it doesn't
+/// fit in the stack
+#[map(name = "EmptyAggregate")]
+static EMPTY_AGGREGATE: Array<FunctionAggregate> = Array::with_max_entries(1, 0);
+
+/// The entry times of the calls that are in progress when the latencies are measured, keyed by
+/// `[function address, pid_tgid, stack pointer]`. The calls that trap never return, so the oldest
+/// ones are evicted.
+#[map(name = "CallStarts")]
+static CALL_STARTS: LruHashMap<[u64; 3], u64> = LruHashMap::with_max_entries(4096, 0);
+
+/// The latencies of the calls of every function on every CPU when the latencies are measured,
+/// keyed by the function address
+#[map(name = "CallLatencies")]
+static CALL_LATENCIES: PerCpuHashMap<u64, LatencyHistogram> =
+    PerCpuHashMap::with_max_entries(1024, 0);
+
+/// A zeroed histogram that the histograms of the functions are created from, since it doesn't
+/// fit in the stack
+#[map(name = "EmptyLatencies")]
+static EMPTY_LATENCIES: Array<LatencyHistogram> = Array::with_max_entries(1, 0);
+
+/// The calls of every function on every CPU that are not sent to `FunctionCalls`, counted by
+/// their [`DropReason`] and keyed by the function address
+#[map(name = "DropCounts")]
+static DROP_COUNTS: PerCpuHashMap<u64, DropCounters> = PerCpuHashMap::with_max_entries(1024, 0);
+
+/// The calls that are dropped by their filters or skipped by the throttling, keyed by
+/// `[function address, stack pointer]`, so that their exits are dropped too. The calls that trap
+/// never exit, so the oldest ones are evicted.
+#[map(name = "DroppedCalls")]
+static DROPPED_CALLS: LruHashMap<[u64; 2], u8> = LruHashMap::with_max_entries(4096, 0);
+
+/// Maps the address of a `ret` instruction to the address of the function that it belongs to
+#[map(name = "FunctionExits")]
+static FUNC_EXITS: HashMap<u64, u64> = HashMap::with_max_entries(4096, 0);
+
+/// The id of the current window of the breakpoint scheduler, `0` if the breakpoints are not
+/// scheduled
+#[map(name = "SchedulerWindow")]
+static SCHEDULER_WINDOW: Array<u64> = Array::with_max_entries(1, 0);
+
+/// Whether the call stack is captured on every event, which is set by the userspace at load time
+#[unsafe(no_mangle)]
+static CAPTURE_STACKS: u8 = 0;
+
+/// Whether the calls are aggregated in `CallAggregates` instead of being sent to `FunctionCalls`,
+/// which is set by the userspace at load time
 #[unsafe(no_mangle)]
-static MEM_BASE: u64 = 0;
+static AGGREGATE_CALLS: u8 = 0;
+
+/// Whether the latencies of the calls are measured in `CallLatencies` instead of sending the calls
+/// to `FunctionCalls`, which is set by the userspace at load time
+#[unsafe(no_mangle)]
+static MEASURE_LATENCY: u8 = 0;
+
+/// The process that `sample_cpu` samples, which is set by the userspace at load time
+#[unsafe(no_mangle)]
+static SAMPLED_TGID: u32 = 0;
+
+#[map(name = "Samples")]
+static SAMPLES: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+#[map(name = "SampleScratch")]
+static SAMPLE_SCRATCH: PerCpuArray<[u8; SAMPLE_HEADER_LEN + MAX_STACK_DEPTH * size_of::<u64>()]> =
+    PerCpuArray::with_max_entries(1, 0);
+
+/// The records are built here before they are copied to the ring buffer with their actual
+/// length, since they don't fit in the stack and a ring buffer reservation needs a constant size
+#[map(name = "EventScratch")]
+static EVENT_SCRATCH: PerCpuArray<[u8; MAX_EVENT_LEN]> = PerCpuArray::with_max_entries(1, 0);
+
+/// The length of the payload of an [`EventKind::InvalidGuestPointer`] event
+const INVALID_GUEST_POINTER_LEN: usize = size_of::<u32>() + size_of::<u64>() * 3;
+
+/// The cost of a call in the tokens of the rate limit, which are kept in billionths of a call so
+/// that they can be refilled every nanosecond
+const TOKENS_PER_CALL: u64 = 1_000_000_000;
 
-#[repr(C)]
-pub struct FunctionCallEvent {
-    pub addr: u64,
-    pub data: [u8; MAX_DATA_LEN],
+/// Offset of `base` in wasmtime's `VMMemoryDefinition`
+const VMMEMORY_DEFINITION_BASE: u64 = 0;
+/// Offset of `current_length` in wasmtime's `VMMemoryDefinition`
+const VMMEMORY_DEFINITION_CURRENT_LENGTH: u64 = 8;
+
+/// The linear memory of the instance that a function is called on
+struct LinearMemory {
+    base: u64,
+    len: u64,
+}
+
+enum ParseError {
+    /// The event cannot be captured and is discarded
+    Failed(DropReason),
+    /// A `Bytes` param points outside of the linear memory, which is reported instead of
+    /// reading host memory
+    InvalidGuestPointer {
+        param_index: u32,
+        pointer: u64,
+        len: u64,
+        memory_len: u64,
+    },
+}
+
+/// The values of the params of a call, which the filters are evaluated on
+#[derive(Default)]
+struct ParamValues {
+    /// The numeric params, sign-extended for the signed params
+    numbers: [u64; MAX_PARAM_COUNT],
+    /// `(offset in the payload, captured length, length)` of the `Bytes` params
+    bytes: [(u32, u32, u32); MAX_PARAM_COUNT],
+}
+
+impl From<DropReason> for ParseError {
+    fn from(e: DropReason) -> Self {
+        ParseError::Failed(e)
+    }
 }
 
 #[perf_event]
 pub fn trace_function_call(ctx: PerfEventContext) -> u32 {
-    match try_trace_function_call(ctx) {
+    info!(&ctx, "within the probe");
+
+    let p = ctx.ctx as *const bpf_perf_event_data;
+    let regs = unsafe { core::ptr::addr_of!((*p).regs) };
+
+    match try_trace_function_call(ctx.ctx, regs, read_address(&ctx)) {
         Ok(ret) => ret,
         Err(ret) => ret,
     }
 }
 
-fn discard(entry: RingBufBytes<'_>, e: u32) -> u32 {
-    entry.discard(0);
-    e
+/// Same as `trace_function_call` but for the uprobes that are attached to the functions in a
+/// precompiled `.cwasm` file
+#[uprobe]
+pub fn trace_function_call_uprobe(ctx: ProbeContext) -> u32 {
+    let regs = ctx.regs as *const pt_regs;
+    // the instruction pointer is at the probed instruction when the uprobe hits
+    let address = read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rip) });
+
+    match try_trace_function_call(ctx.regs as *mut c_void, regs, address) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
 }
 
-fn try_trace_function_call(ctx: PerfEventContext) -> Result<u32, u32> {
-    info!(&ctx, "within the probe");
+/// Samples the user stack of the `SAMPLED_TGID` process on a CPU clock, which is attached to
+/// every CPU since the samples of a process can be taken on any of them
+#[perf_event]
+pub fn sample_cpu(ctx: PerfEventContext) -> u32 {
+    match try_sample_cpu(&ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+fn try_sample_cpu(ctx: &PerfEventContext) -> Result<u32, u32> {
+    let pid_tgid = unsafe { bpf_get_current_pid_tgid() };
+    if (pid_tgid >> 32) as u32 != unsafe { core::ptr::read_volatile(&SAMPLED_TGID) } {
+        return Ok(0);
+    }
+
+    let record = unsafe { &mut *SAMPLE_SCRATCH.get_ptr_mut(0).ok_or(1u32)? };
+    let (head, frames) = record.split_at_mut(SAMPLE_HEADER_LEN);
+    // only the instruction pointer is needed for the histogram
+    let frames = if unsafe { core::ptr::read_volatile(&CAPTURE_STACKS) } != 0 {
+        frames
+    } else {
+        &mut frames[..size_of::<u64>()]
+    };
 
-    let mem_base = unsafe { core::ptr::read_volatile(&MEM_BASE) };
+    // the user stack is walked from the user registers of the task, so the samples that are
+    // taken in the kernel are attributed to the code that entered the kernel
+    let len = unsafe {
+        bpf_get_stack(
+            ctx.ctx,
+            frames.as_mut_ptr() as *mut c_void,
+            frames.len() as u32,
+            BPF_F_USER_STACK as u64,
+        )
+    };
+    if len <= 0 {
+        return Err(1);
+    }
+    let stack_depth = len as usize / size_of::<u64>();
+
+    let header = SampleHeader {
+        timestamp: unsafe { bpf_ktime_get_ns() },
+        tgid: (pid_tgid >> 32) as u32,
+        tid: pid_tgid as u32,
+        cpu: unsafe { bpf_get_smp_processor_id() },
+        stack_depth: stack_depth as u32,
+    };
+    unsafe { core::ptr::write_unaligned(head.as_mut_ptr() as *mut SampleHeader, header) };
 
-    let address = read_address(&ctx);
+    let Some(record) = record.get(..SAMPLE_HEADER_LEN + stack_depth * size_of::<u64>()) else {
+        return Err(1);
+    };
+    SAMPLES.output(record, 0).map_err(|_| 1u32)?;
 
-    let maybe_function_meta = unsafe { FUNC_TYPES.get(address) };
-    let Some(function_meta) = maybe_function_meta else {
-        return Ok(0);
+    Ok(0)
+}
+
+fn try_trace_function_call(
+    ctx: *mut c_void,
+    regs: *const pt_regs,
+    address: u64,
+) -> Result<u32, u32> {
+    let (function_address, function_meta, kind) =
+        if let Some(function_meta) = unsafe { FUNC_TYPES.get(address) } {
+            (address, function_meta, EventKind::Entry)
+        } else if let Some(function_address) = unsafe { FUNC_EXITS.get(address) } {
+            let Some(function_meta) = (unsafe { FUNC_TYPES.get(function_address) }) else {
+                return Ok(0);
+            };
+            (*function_address, function_meta, EventKind::Exit)
+        } else {
+            return Ok(0);
+        };
+
+    match trace_call(ctx, regs, function_address, function_meta, kind) {
+        Ok(()) => Ok(0),
+        Err(reason) => {
+            count_drop(function_address, reason);
+            Err(1)
+        }
+    }
+}
+
+/// Traces a hit of the entry or an exit of the function at `function_address`, and returns why
+/// the call is dropped if it's not traced.
+#[inline(always)]
+fn trace_call(
+    ctx: *mut c_void,
+    regs: *const pt_regs,
+    function_address: u64,
+    function_meta: &FunctionMetadata,
+    kind: EventKind,
+) -> Result<(), DropReason> {
+    // the time is taken before the params are read, so that it's not in the latencies
+    let timestamp = unsafe { bpf_ktime_get_ns() };
+
+    // only the entries are aggregated
+    let aggregate = unsafe { core::ptr::read_volatile(&AGGREGATE_CALLS) } != 0;
+    if aggregate && matches!(kind, EventKind::Exit) {
+        return Ok(());
+    }
+
+    let stack_ptr = read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rsp) });
+
+    let filters = unsafe { FUNC_FILTERS.get(function_address) };
+    let call_key = [function_address, stack_ptr];
+    if matches!(kind, EventKind::Exit)
+        && (filters.is_some() || function_meta.is_throttled())
+        && unsafe { DROPPED_CALLS.get(call_key) }.is_some()
+    {
+        let _ = DROPPED_CALLS.remove(call_key);
+        return Ok(());
+    }
+
+    let pid_tgid = unsafe { bpf_get_current_pid_tgid() };
+
+    let latency = unsafe { core::ptr::read_volatile(&MEASURE_LATENCY) } != 0;
+    let start_key = [function_address, pid_tgid, stack_ptr];
+    if latency && matches!(kind, EventKind::Exit) {
+        record_latency(function_address, start_key, timestamp)?;
+        return Ok(());
+    }
+
+    let window = SCHEDULER_WINDOW.get(0).copied().unwrap_or(0);
+
+    let record = unsafe { &mut *EVENT_SCRATCH.get_ptr_mut(0).ok_or(DropReason::MapFull)? };
+    let (head, payload) = record.split_at_mut(EVENT_HEADER_LEN);
+
+    let mut values = ParamValues::default();
+
+    let res = match kind {
+        EventKind::Entry => {
+            parse_function_params_into_buf(regs, function_meta, payload, &mut values)
+                .map(|len| (kind, function_meta.param_count as u8, len))
+        }
+        EventKind::Exit => parse_return_value_into_buf(regs, function_meta, payload)
+            .map(|len| (kind, (len > 0) as u8, len))
+            .map_err(ParseError::Failed),
+        EventKind::InvalidGuestPointer => return Err(DropReason::InvalidMetadata),
     };
 
-    let mut entry = FUNCTION_CALLS.reserve_bytes(1024, 0).ok_or(1u32)?;
-    let (head, tail) = unsafe { entry.split_at_mut_unchecked(size_of::<c_ulong>()) };
-    head[0..size_of::<c_ulong>()].copy_from_slice(&read_address(&ctx).to_le_bytes());
+    let (kind, param_count, payload_len) = match res {
+        Ok(res) => res,
+        Err(ParseError::InvalidGuestPointer {
+            param_index,
+            pointer,
+            len,
+            memory_len,
+        }) => {
+            // the params that are already written are overwritten
+            payload[0..4].copy_from_slice(&param_index.to_le_bytes());
+            payload[4..12].copy_from_slice(&pointer.to_le_bytes());
+            payload[12..20].copy_from_slice(&len.to_le_bytes());
+            payload[20..28].copy_from_slice(&memory_len.to_le_bytes());
+            (EventKind::InvalidGuestPointer, 0, INVALID_GUEST_POINTER_LEN)
+        }
+        Err(ParseError::Failed(e)) => return Err(e),
+    };
 
-    if parse_function_params_into_buf(&ctx, mem_base, function_meta, tail).is_err() {
-        return Err(discard(entry, 1));
+    if matches!(kind, EventKind::Entry)
+        && let Some(filters) = filters
+        && !matches_filters(filters, function_meta, &values, payload)
+    {
+        if !aggregate {
+            let _ = DROPPED_CALLS.insert(call_key, 1, 0);
+        }
+        count_drop(function_address, DropReason::Filtered);
+        return Ok(());
     }
 
-    entry.submit(0);
+    if latency {
+        let _ = CALL_STARTS.insert(start_key, timestamp, 0);
+        return Ok(());
+    }
 
-    Ok(0)
+    if aggregate {
+        aggregate_call(function_address, function_meta, kind, &values)?;
+        return Ok(());
+    }
+
+    // the throttling applies to the calls that match the filters, so that the skipped calls can
+    // be scaled against them
+    if !matches!(kind, EventKind::Exit)
+        && function_meta.is_throttled()
+        && !take_call(function_address, function_meta)?
+    {
+        let _ = DROPPED_CALLS.insert(call_key, 1, 0);
+        return Ok(());
+    }
+
+    let stack_depth = if unsafe { core::ptr::read_volatile(&CAPTURE_STACKS) } != 0 {
+        let stack = payload
+            .get_mut(payload_len..payload_len + MAX_STACK_DEPTH * size_of::<u64>())
+            .ok_or(DropReason::RecordTooLong)?;
+        capture_stack(ctx, function_address, stack_ptr, stack)
+    } else {
+        0
+    };
+
+    let len = EVENT_HEADER_LEN + payload_len + stack_depth * size_of::<u64>();
+
+    let header = EventHeader {
+        version: EVENT_VERSION,
+        kind: kind as u8,
+        param_count,
+        stack_depth: stack_depth as u8,
+        len: len as u32,
+        addr: function_address,
+        stack_pointer: stack_ptr,
+        window,
+        timestamp,
+        tgid: (pid_tgid >> 32) as u32,
+        tid: pid_tgid as u32,
+        cpu: unsafe { bpf_get_smp_processor_id() },
+        _padding: 0,
+    };
+    unsafe { core::ptr::write_unaligned(head.as_mut_ptr() as *mut EventHeader, header) };
+
+    let record = record.get(..len).ok_or(DropReason::RecordTooLong)?;
+    FUNCTION_CALLS
+        .output(record, 0)
+        .map_err(|_| DropReason::RingBufferFull)?;
+
+    Ok(())
+}
+
+/// Counts a call of the function at `function_address` that is dropped for `reason` on the
+/// current CPU.
+#[inline(always)]
+fn count_drop(function_address: u64, reason: DropReason) {
+    let counters = match DROP_COUNTS.get_ptr_mut(function_address) {
+        Some(counters) => counters,
+        None => {
+            // another CPU can insert the counters first, which are not replaced
+            let _ = DROP_COUNTS.insert(
+                function_address,
+                DropCounters::default(),
+                BPF_NOEXIST as u64,
+            );
+            let Some(counters) = DROP_COUNTS.get_ptr_mut(function_address) else {
+                return;
+            };
+            counters
+        }
+    };
+
+    if let Some(count) = unsafe { (*counters).counts.get_mut(reason as usize) } {
+        *count += 1;
+    }
+}
+
+/// Counts a call of a throttled function on the current CPU and returns whether it's traced.
+///
+/// Every `sample_every`th call is traced, starting with the first one, and the sampled calls are
+/// then limited by a token bucket that is refilled with `rate_limit` calls per second up to
+/// `rate_burst` calls.
+#[inline(always)]
+fn take_call(function_address: u64, meta: &FunctionMetadata) -> Result<bool, DropReason> {
+    let counters = match CALL_COUNTERS.get_ptr_mut(function_address) {
+        Some(counters) => counters,
+        None => {
+            // the counters of the other CPUs are zeroed, and a zeroed bucket is refilled fully
+            // on its first call. Another CPU can insert them first, which is not replaced.
+            let _ = CALL_COUNTERS.insert(
+                function_address,
+                CallCounters::default(),
+                BPF_NOEXIST as u64,
+            );
+            CALL_COUNTERS
+                .get_ptr_mut(function_address)
+                .ok_or(DropReason::MapFull)?
+        }
+    };
+    let counters = unsafe { &mut *counters };
+
+    counters.calls += 1;
+
+    if meta.sample_every > 1 && (counters.calls - 1) % meta.sample_every as u64 != 0 {
+        counters.sampled_out += 1;
+        return Ok(false);
+    }
+
+    if meta.rate_limit > 0 {
+        let now = unsafe { bpf_ktime_get_ns() };
+        let rate = meta.rate_limit as u64;
+        let capacity = meta.rate_burst.max(1) as u64 * TOKENS_PER_CALL;
+
+        // the refill is capped before multiplying, which would overflow after a long idle time
+        let elapsed = now.saturating_sub(counters.last_refill);
+        let refill = if elapsed >= capacity / rate {
+            capacity
+        } else {
+            elapsed * rate
+        };
+        counters.tokens = (counters.tokens + refill).min(capacity);
+        counters.last_refill = now;
+
+        if counters.tokens < TOKENS_PER_CALL {
+            counters.rate_limited += 1;
+            return Ok(false);
+        }
+        counters.tokens -= TOKENS_PER_CALL;
+    }
+
+    Ok(true)
+}
+
+/// Adds a call of a function to its aggregates on the current CPU.
+#[inline(always)]
+fn aggregate_call(
+    function_address: u64,
+    meta: &FunctionMetadata,
+    kind: EventKind,
+    values: &ParamValues,
+) -> Result<(), DropReason> {
+    let aggregate = match CALL_AGGREGATES.get_ptr_mut(function_address) {
+        Some(aggregate) => aggregate,
+        None => {
+            let empty = EMPTY_AGGREGATE.get(0).ok_or(DropReason::MapFull)?;
+            let _ = CALL_AGGREGATES.insert(function_address, empty, BPF_NOEXIST as u64);
+            CALL_AGGREGATES
+                .get_ptr_mut(function_address)
+                .ok_or(DropReason::MapFull)?
+        }
+    };
+    let aggregate = unsafe { &mut *aggregate };
+
+    if matches!(kind, EventKind::InvalidGuestPointer) {
+        aggregate.calls += 1;
+        aggregate.invalid_guest_pointers += 1;
+        return Ok(());
+    }
+
+    // the statistics are not initialized until the params of a call are aggregated
+    let first = aggregate.calls == aggregate.invalid_guest_pointers;
+    aggregate.calls += 1;
+
+    for i in 0..MAX_PARAM_COUNT {
+        if i >= meta.param_count {
+            break;
+        }
+
+        let signed = matches!(
+            meta.param_types[i],
+            ParamType::I8 | ParamType::I32 | ParamType::I64
+        );
+        let unsigned = matches!(
+            meta.param_types[i],
+            ParamType::U8 | ParamType::U32 | ParamType::U64
+        );
+
+        if signed || unsigned {
+            let value = values.numbers[i];
+            let param = &mut aggregate.params[i];
+            let (below, above) = if signed {
+                (
+                    (value as i64) < (param.min as i64),
+                    (value as i64) > (param.max as i64),
+                )
+            } else {
+                (value < param.min, value > param.max)
+            };
+            if first || below {
+                param.min = value;
+            }
+            if first || above {
+                param.max = value;
+            }
+            param.sum = param.sum.wrapping_add(value);
+        } else if matches!(meta.param_types[i], ParamType::Bytes) {
+            let bucket = log2_bucket(values.bytes[i].2 as u64, LEN_BUCKETS);
+            if let Some(count) = aggregate.bytes_lens[i].get_mut(bucket) {
+                *count += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the latency of a call that returns at `timestamp` to the histogram of its function on
+/// the current CPU. The calls whose entries are not seen are ignored.
+#[inline(always)]
+fn record_latency(
+    function_address: u64,
+    start_key: [u64; 3],
+    timestamp: u64,
+) -> Result<(), DropReason> {
+    let Some(start) = (unsafe { CALL_STARTS.get(start_key) }).copied() else {
+        return Ok(());
+    };
+    let _ = CALL_STARTS.remove(start_key);
+
+    let histogram = match CALL_LATENCIES.get_ptr_mut(function_address) {
+        Some(histogram) => histogram,
+        None => {
+            let empty = EMPTY_LATENCIES.get(0).ok_or(DropReason::MapFull)?;
+            let _ = CALL_LATENCIES.insert(function_address, empty, BPF_NOEXIST as u64);
+            CALL_LATENCIES
+                .get_ptr_mut(function_address)
+                .ok_or(DropReason::MapFull)?
+        }
+    };
+    let histogram = unsafe { &mut *histogram };
+
+    let latency = timestamp.saturating_sub(start);
+    histogram.calls += 1;
+    histogram.total_ns += latency;
+    histogram.max_ns = histogram.max_ns.max(latency);
+    if let Some(count) = histogram
+        .buckets
+        .get_mut(log2_bucket(latency, LATENCY_BUCKETS))
+    {
+        *count += 1;
+    }
+
+    Ok(())
+}
+
+/// Returns the bucket of `value` in a log2 histogram with `buckets` buckets, which is the number
+/// of its significant bits. The values that don't fit are in the last bucket.
+#[inline(always)]
+fn log2_bucket(mut value: u64, buckets: usize) -> usize {
+    if value == 0 {
+        return 0;
+    }
+
+    let mut bucket = 1;
+    for shift in [32, 16, 8, 4, 2, 1] {
+        if value >= 1 << shift {
+            value >>= shift;
+            bucket += shift;
+        }
+    }
+
+    bucket.min(buckets - 1)
+}
+
+/// Writes the return addresses of the user stack into `buf` and returns the number of frames.
+///
+/// The events are captured at the first instruction or at a `ret` of the function, where its
+/// frame is not set up, so walking the frame pointers skips the caller. The function itself and
+/// the return address at the top of the stack are written as the first two frames instead.
+#[inline(always)]
+fn capture_stack(ctx: *mut c_void, function_address: u64, stack_ptr: u64, buf: &mut [u8]) -> usize {
+    buf[0..8].copy_from_slice(&function_address.to_le_bytes());
+
+    let Ok(return_address) = (unsafe { bpf_probe_read_user(stack_ptr as *const u64) }) else {
+        return 1;
+    };
+    buf[8..16].copy_from_slice(&return_address.to_le_bytes());
+
+    let frames = &mut buf[16..];
+    // the first frame of the walk is the instruction pointer, which is skipped
+    let len = unsafe {
+        bpf_get_stack(
+            ctx,
+            frames.as_mut_ptr() as *mut c_void,
+            frames.len() as u32,
+            BPF_F_USER_STACK as u64 | 1,
+        )
+    };
+
+    2 + len.max(0) as usize / size_of::<u64>()
+}
+
+#[inline(always)]
+fn parse_return_value_into_buf(
+    regs: *const pt_regs,
+    function_meta: &FunctionMetadata,
+    buf: &mut [u8],
+) -> Result<usize, DropReason> {
+    // the breakpoint hits before `ret` is executed, so the return value is already in `rax`
+    let value = read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rax) });
+
+    let len = match function_meta.ret_type {
+        ParamType::Unspecified => 0,
+        ParamType::I8 | ParamType::U8 => {
+            buf[0] = value as u8;
+            size_of::<u8>()
+        }
+        ParamType::I32 | ParamType::U32 => {
+            buf[0..size_of::<u32>()].copy_from_slice(&(value as u32).to_le_bytes());
+            size_of::<u32>()
+        }
+        ParamType::I64 | ParamType::U64 => {
+            buf[0..size_of::<u64>()].copy_from_slice(&(value as u64).to_le_bytes());
+            size_of::<u64>()
+        }
+        _ => return Err(DropReason::UnsupportedType),
+    };
+
+    Ok(len)
 }
 
 #[inline(always)]
 fn parse_function_params_into_buf(
-    ctx: &PerfEventContext,
-    mem_base: c_ulong,
+    regs: *const pt_regs,
     function_meta: &FunctionMetadata,
     buf: &mut [u8],
-) -> Result<u32, u32> {
+    values: &mut ParamValues,
+) -> Result<usize, ParseError> {
     // slices consume two registers while numeric values consume only one
     let mut raw_param_offset = 0;
 
+    let buf_len = buf.len();
     let mut tail = buf;
 
     if function_meta.param_count > function_meta.param_types.len() {
-        return Err(1);
+        return Err(ParseError::Failed(DropReason::InvalidMetadata));
     }
     for i in 0..function_meta.param_count {
         match function_meta.param_types[i] {
             wasm_tracer_abi::ParamType::I8 | wasm_tracer_abi::ParamType::U8 => {
-                let value = read_word_at_index(ctx, raw_param_offset)?;
+                let value = read_word_at_index(regs, raw_param_offset)?;
+                if let Some(number) = values.numbers.get_mut(i) {
+                    *number = if matches!(function_meta.param_types[i], ParamType::I8) {
+                        value as i8 as u64
+                    } else {
+                        value as u8 as u64
+                    };
+                }
                 let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(size_of::<u8>()) };
                 head.iter_mut()
                     .zip((value as u8).to_le_bytes().into_iter())
@@ -95,7 +719,14 @@ fn parse_function_params_into_buf(
                 raw_param_offset += 1;
             }
             wasm_tracer_abi::ParamType::I32 | wasm_tracer_abi::ParamType::U32 => {
-                let value = read_word_at_index(ctx, raw_param_offset)?;
+                let value = read_word_at_index(regs, raw_param_offset)?;
+                if let Some(number) = values.numbers.get_mut(i) {
+                    *number = if matches!(function_meta.param_types[i], ParamType::I32) {
+                        value as i32 as u64
+                    } else {
+                        value as u32 as u64
+                    };
+                }
                 let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(size_of::<u32>()) };
                 head.iter_mut()
                     .zip((value as u32).to_le_bytes().into_iter())
@@ -105,7 +736,10 @@ fn parse_function_params_into_buf(
                 raw_param_offset += 1;
             }
             wasm_tracer_abi::ParamType::I64 | wasm_tracer_abi::ParamType::U64 => {
-                let value = read_word_at_index(ctx, raw_param_offset)?;
+                let value = read_word_at_index(regs, raw_param_offset)?;
+                if let Some(number) = values.numbers.get_mut(i) {
+                    *number = value as u64;
+                }
                 let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(size_of::<u64>()) };
                 head.iter_mut()
                     .zip((value as u64).to_le_bytes().into_iter())
@@ -114,56 +748,187 @@ fn parse_function_params_into_buf(
                 tail = new_tail;
                 raw_param_offset += 1;
             }
-            wasm_tracer_abi::ParamType::F32 => return Err(0),
-            wasm_tracer_abi::ParamType::F64 => return Err(0),
+            wasm_tracer_abi::ParamType::F32 => {
+                return Err(ParseError::Failed(DropReason::UnsupportedType));
+            }
+            wasm_tracer_abi::ParamType::F64 => {
+                return Err(ParseError::Failed(DropReason::UnsupportedType));
+            }
             wasm_tracer_abi::ParamType::Bytes => {
-                let pointer = read_word_at_index(ctx, raw_param_offset)?;
-                let len = read_word_at_index(ctx, raw_param_offset + 1)?;
+                // the pointer and the length are `i32`s, the upper halves of the registers are
+                // not defined
+                let pointer = read_word_at_index(regs, raw_param_offset)? as u32 as u64;
+                let len = read_word_at_index(regs, raw_param_offset + 1)? as u32 as u64;
+                let memory = read_linear_memory(regs, function_meta)?;
+
+                if pointer.checked_add(len).is_none_or(|end| end > memory.len) {
+                    return Err(ParseError::InvalidGuestPointer {
+                        param_index: i as u32,
+                        pointer,
+                        len,
+                        memory_len: memory.len,
+                    });
+                }
 
-                // TODO(aeryz): this check is wrong
-                if len > 20 as u64 {
-                    return Err(1);
+                if tail.len() < 2 * size_of::<u32>() {
+                    return Err(ParseError::Failed(DropReason::RecordTooLong));
                 }
 
+                // long values are truncated to the capture limit, and to the space that is left
+                // in the event
+                let captured = len
+                    .min(function_meta.capture_limits[i].min(MAX_CAPTURE_LIMIT) as u64)
+                    .min((tail.len() - 2 * size_of::<u32>()) as u64);
+
                 let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(4) };
                 head.iter_mut()
                     .zip((len as u32).to_le_bytes().into_iter())
                     .for_each(|(x, y)| *x = y);
                 tail = new_tail;
 
-                let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(len as usize) };
+                let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(4) };
+                head.iter_mut()
+                    .zip((captured as u32).to_le_bytes().into_iter())
+                    .for_each(|(x, y)| *x = y);
+                tail = new_tail;
+
+                if let Some(bytes) = values.bytes.get_mut(i) {
+                    *bytes = ((buf_len - tail.len()) as u32, captured as u32, len as u32);
+                }
+
+                let (head, new_tail) = unsafe { tail.split_at_mut_unchecked(captured as usize) };
                 head.iter_mut().enumerate().try_for_each(|(i, x)| {
                     unsafe {
-                        *x = bpf_probe_read_user((mem_base + pointer + i as u64) as *const u8)
-                            .map_err(|x| x as u32)?;
+                        *x = bpf_probe_read_user((memory.base + pointer + i as u64) as *const u8)
+                            .map_err(|_| DropReason::ReadFailed)?;
                     }
 
-                    Result::<(), u32>::Ok(())
+                    Result::<(), DropReason>::Ok(())
                 })?;
 
                 tail = new_tail;
                 raw_param_offset += 2;
             }
-            _ => return Err(0),
+            _ => return Err(ParseError::Failed(DropReason::UnsupportedType)),
         }
     }
-    Ok(0)
+    Ok(buf_len - tail.len())
+}
+
+/// Whether the params of a call match all of the `filters` of the function.
+#[inline(always)]
+fn matches_filters(
+    filters: &FunctionFilters,
+    function_meta: &FunctionMetadata,
+    values: &ParamValues,
+    payload: &[u8],
+) -> bool {
+    for filter in &filters.filters {
+        let i = filter.param_index as usize;
+        let (Some(number), Some((offset, captured, len)), Some(ty)) = (
+            values.numbers.get(i),
+            values.bytes.get(i),
+            function_meta.param_types.get(i),
+        ) else {
+            return false;
+        };
+
+        let ordering = match ty {
+            ParamType::I8 | ParamType::I32 | ParamType::I64 => {
+                (*number as i64).cmp(&(filter.value as i64))
+            }
+            _ => number.cmp(&filter.value),
+        };
+
+        let matched = match FilterOp::from_u8(filter.op) {
+            Some(FilterOp::None) => true,
+            Some(FilterOp::Eq) => ordering.is_eq(),
+            Some(FilterOp::Ne) => ordering.is_ne(),
+            Some(FilterOp::Lt) => ordering.is_lt(),
+            Some(FilterOp::Le) => ordering.is_le(),
+            Some(FilterOp::Gt) => ordering.is_gt(),
+            Some(FilterOp::Ge) => ordering.is_ge(),
+            Some(FilterOp::BytesEq) => {
+                *len == filter.pattern_len as u32
+                    && starts_with(payload, *offset, *captured, filter)
+            }
+            Some(FilterOp::StartsWith) => starts_with(payload, *offset, *captured, filter),
+            None => false,
+        };
+        if !matched {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Whether the captured bytes at `offset` in the payload start with the pattern of `filter`.
+#[inline(always)]
+fn starts_with(payload: &[u8], offset: u32, captured: u32, filter: &Filter) -> bool {
+    let pattern_len = filter.pattern_len as usize;
+    if (captured as usize) < pattern_len {
+        return false;
+    }
+
+    for j in 0..MAX_FILTER_PATTERN_LEN {
+        if j >= pattern_len {
+            break;
+        }
+        if payload.get(offset as usize + j) != Some(&filter.pattern[j]) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Reads the linear memory of the instance that the function is called on. The memory
+/// definition is read at every call, since every instance has its own memory and the memory
+/// can move or change its size when it grows.
+#[inline(always)]
+fn read_linear_memory(
+    regs: *const pt_regs,
+    function_meta: &FunctionMetadata,
+) -> Result<LinearMemory, DropReason> {
+    if function_meta.memory_offset == 0 {
+        return Err(DropReason::NoLinearMemory);
+    }
+
+    // the callee `vmctx` is in `rdi` at the entry of the function
+    let vmctx = read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rdi) });
+
+    unsafe {
+        let definition =
+            bpf_probe_read_user((vmctx + function_meta.memory_offset as u64) as *const u64)
+                .map_err(|_| DropReason::ReadFailed)?;
+        Ok(LinearMemory {
+            base: bpf_probe_read_user((definition + VMMEMORY_DEFINITION_BASE) as *const u64)
+                .map_err(|_| DropReason::ReadFailed)?,
+            len: bpf_probe_read_user(
+                (definition + VMMEMORY_DEFINITION_CURRENT_LENGTH) as *const u64,
+            )
+            .map_err(|_| DropReason::ReadFailed)?,
+        })
+    }
 }
 
 #[inline(always)]
 /// Reads a single word at an `index` based on the [System V calling convention](https://wiki.osdev.org/System_V_ABI)
-fn read_word_at_index(ctx: &PerfEventContext, index: usize) -> Result<c_ulong, u32> {
+fn read_word_at_index(regs: *const pt_regs, index: usize) -> Result<c_ulong, DropReason> {
     let val = match index {
-        0 => read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.rcx) }),
-        1 => read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.r8) }),
-        2 => read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.r9) }),
+        // `rdi` and `rsi` are the callee and caller `vmctx`s, the wasm params start from `rdx`
+        0 => read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rdx) }),
+        1 => read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rcx) }),
+        2 => read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).r8) }),
+        3 => read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).r9) }),
         n => {
-            let stack_ptr = read_register(&ctx, |p| unsafe { core::ptr::addr_of!((*p).regs.rsp) });
-            // `n - 2` since n starts from 3
-            let stack_offset = (size_of::<c_ulong>() * (n - 2)) as c_ulong;
+            let stack_ptr = read_register(regs, |p| unsafe { core::ptr::addr_of!((*p).rsp) });
+            // `n - 3` since n starts from 4 and the return address is at `rsp`
+            let stack_offset = (size_of::<c_ulong>() * (n - 3)) as c_ulong;
             unsafe {
                 bpf_probe_read_user((stack_ptr + stack_offset) as *const c_ulong)
-                    .map_err(|e| e as u32)?
+                    .map_err(|_| DropReason::ReadFailed)?
             }
         }
     };
@@ -172,13 +937,8 @@ fn read_word_at_index(ctx: &PerfEventContext, index: usize) -> Result<c_ulong, u
 }
 
 #[inline(always)]
-fn read_register(
-    ctx: &PerfEventContext,
-    addr_of_reg: fn(*const bpf_perf_event_data) -> *const u64,
-) -> c_ulong {
-    let p = ctx.ctx as *const bpf_perf_event_data;
-
-    unsafe { core::ptr::read_volatile(addr_of_reg(p)) }
+fn read_register(regs: *const pt_regs, addr_of_reg: fn(*const pt_regs) -> *const u64) -> c_ulong {
+    unsafe { core::ptr::read_volatile(addr_of_reg(regs)) }
 }
 
 #[inline(always)]